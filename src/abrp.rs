@@ -0,0 +1,177 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use std::{collections::BTreeMap, error::Error};
+
+use crate::geo::distance_m;
+use crate::history::Snapshot;
+use crate::psa::api::ApiClient;
+use crate::psa::model::{VehicleStatus, VehiclesListElement};
+use crate::watch::WatchHandler;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AbrpConfig {
+    #[serde(default = "default_url")]
+    pub url: String,
+    pub api_key: String,
+    // user token per VIN, vehicles without token are not sent
+    pub tokens: BTreeMap<String, String>,
+}
+
+fn default_url() -> String {
+    "https://api.iternio.com/1/tlm/send".to_owned()
+}
+
+/// Telemetry record in the schema accepted by route planners.
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Telemetry {
+    pub utc: i64,
+    pub soc: Option<u32>,
+    pub speed: Option<f64>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub is_charging: bool,
+    pub is_parked: bool,
+    pub power: Option<f64>,
+    pub ext_temp: f32,
+    pub odometer: f32,
+    pub est_battery_range: Option<u32>,
+}
+
+impl Telemetry {
+    /// Maps the snapshot to telemetry, speed and power are not reported by
+    /// the API and estimated from the previous snapshot of the vehicle.
+    pub fn from_snapshot(cur: &Snapshot, prev: Option<&Snapshot>) -> Telemetry {
+        let is_charging = cur.charging_status.as_deref() == Some("InProgress");
+        let elapsed = prev
+            .filter(|p| p.updated_at < cur.updated_at)
+            .map(|p| (p, (cur.updated_at - p.updated_at).num_seconds() as f64 / 3600.0));
+
+        let speed = if cur.moving {
+            elapsed
+                .and_then(|(p, hours)| Some(distance_m(p.position()?, cur.position()?) / 1000.0 / hours))
+                .map(|kmh| kmh.round())
+        } else {
+            Some(0.0)
+        };
+
+        // negative power is charging
+        let power = elapsed.and_then(|(p, hours)| {
+            let capacity = cur.battery_capacity_kwh()?;
+            let delta = cur.soc? as f64 - p.soc? as f64;
+            Some(-(delta / 100.0 * capacity / hours * 10.0).round() / 10.0)
+        }).or(if !is_charging && !cur.moving { Some(0.0) } else { None });
+
+        Telemetry {
+            utc: cur.updated_at.timestamp(),
+            soc: cur.soc,
+            speed,
+            lat: cur.latitude,
+            lon: cur.longitude,
+            is_charging,
+            is_parked: !cur.moving && !cur.ignition_on(),
+            power,
+            ext_temp: cur.temperature,
+            odometer: cur.mileage,
+            est_battery_range: cur.autonomy,
+        }
+    }
+}
+
+pub struct AbrpSender {
+    config: AbrpConfig,
+    http: reqwest::blocking::Client,
+    last: BTreeMap<String, Snapshot>,
+}
+
+impl AbrpSender {
+    pub fn new(config: &AbrpConfig) -> AbrpSender {
+        AbrpSender {
+            config: config.clone(),
+            http: reqwest::blocking::Client::new(),
+            last: BTreeMap::new(),
+        }
+    }
+
+    pub fn send(&self, token: &str, telemetry: &Telemetry) -> Result<(), Box<dyn Error>> {
+        self.http.post(&self.config.url)
+            .query(&[("api_key", self.config.api_key.as_str()), ("token", token)])
+            .json(&serde_json::json!({ "tlm": telemetry }))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn last_update(&self, vin: &str) -> Option<DateTime<Utc>> {
+        self.last.get(vin).map(|s| s.updated_at)
+    }
+}
+
+impl WatchHandler for AbrpSender {
+    fn on_status(&mut self, _client: &mut ApiClient, car: &VehiclesListElement, status: &VehicleStatus) -> Result<(), Box<dyn Error>> {
+        let token = match self.config.tokens.get(&car.vin) {
+            Some(token) => token.to_owned(),
+            None => return Ok(()),
+        };
+        // nothing new since the last push
+        if self.last_update(&car.vin) == Some(status.updated_at) {
+            return Ok(());
+        }
+        let snapshot = Snapshot::from_status(&car.vin, status)?;
+        let telemetry = Telemetry::from_snapshot(&snapshot, self.last.get(&car.vin));
+        self.last.insert(car.vin.to_owned(), snapshot);
+        self.send(&token, &telemetry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, car, http_server, start, VIN};
+    use chrono::Duration;
+    use serde_json::json;
+
+    #[test]
+    fn sends_telemetry_of_vehicles_with_token() {
+        let (url, requests) = http_server(|_| (200, "{\"status\": \"ok\"}".to_owned()));
+        let mut sender = AbrpSender::new(&AbrpConfig {
+            url: format!("{}/1/tlm/send", url),
+            api_key: "key".to_owned(),
+            tokens: BTreeMap::from([(VIN.to_owned(), "user token".to_owned())]),
+        });
+        let api = testutil::api_config("http://127.0.0.1:1");
+        let mut client = ApiClient::new(&api);
+
+        let parked = testutil::status(start());
+        let mut charging = testutil::status(start() + Duration::minutes(30));
+        charging.energy[0].level = 90;
+        charging.energy[0].extension.as_mut().unwrap().electric.charging.status = "InProgress".to_owned();
+
+        sender.on_status(&mut client, &car(), &parked).unwrap();
+        // unchanged status is not sent again
+        sender.on_status(&mut client, &car(), &parked).unwrap();
+        sender.on_status(&mut client, &car(), &charging).unwrap();
+        let other = VehiclesListElement { vin: "OTHERVIN".to_owned(), ..car() };
+        sender.on_status(&mut client, &other, &charging).unwrap();
+
+        let first = requests.recv().unwrap();
+        assert_eq!(first.path, "/1/tlm/send?api_key=key&token=user+token");
+        let tlm = &first.json()["tlm"];
+        assert_eq!(tlm["utc"], start().timestamp());
+        assert_eq!(tlm["soc"], 80);
+        assert_eq!(tlm["speed"], 0.0);
+        assert_eq!(tlm["power"], 0.0);
+        assert_eq!(tlm["is_charging"], false);
+        assert_eq!(tlm["is_parked"], true);
+        assert_eq!(tlm["est_battery_range"], 240);
+        assert!((tlm["lat"].as_f64().unwrap() - 48.137).abs() < 1e-5);
+
+        let second = requests.recv().unwrap().json();
+        assert_eq!(second["tlm"]["soc"], 90);
+        assert_eq!(second["tlm"]["is_charging"], true);
+        // 10% of 46 kWh in half an hour
+        assert_eq!(second["tlm"]["power"], json!(-9.2));
+        assert!(requests.try_recv().is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt, fs::{File, OpenOptions}};
+
+use crate::psa::model::VehiclesList;
+
+#[derive(Debug)]
+pub struct CarsCacheError {
+    pub message: String
+}
+
+impl Error for CarsCacheError {}
+
+impl fmt::Display for CarsCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cars Cache Error: {}", self.message)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CarsCache {
+    // missing in caches written by older versions, these count as expired
+    #[serde(default)]
+    pub fetched_at: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    pub cars: VehiclesList,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl CarsCache {
+    pub fn new(cars: VehiclesList) -> CarsCache {
+        CarsCache {
+            fetched_at: Some(Utc::now()),
+            cars,
+        }
+    }
+
+    /// Loads the cache, returns `None` if there is no cache file yet and an
+    /// error if the file can not be parsed.
+    pub fn load(filename: &str) -> Result<Option<CarsCache>, Box<dyn Error>> {
+        let f = match File::open(filename) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        };
+        match serde_yaml::from_reader::<File, CarsCache>(f) {
+            Ok(cache) => Ok(Some(cache)),
+            Err(e) => Err(Box::new(CarsCacheError { message: format!("{} is corrupt: {}", filename, e) })),
+        }
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let f = OpenOptions::new().write(true).create(true).truncate(true).open(filename)?;
+        serde_yaml::to_writer(f, &self)?;
+        Ok(())
+    }
+
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        match self.fetched_at {
+            // an expiry beyond the range of DateTime never passes
+            Some(fetched_at) => fetched_at.checked_add_signed(ttl).is_some_and(|expiry| expiry < Utc::now()),
+            None => true,
+        }
+    }
+
+    pub fn contains(&self, vin: &str) -> bool {
+        self.cars.vehicles.iter().any(|c| c.vin.eq(vin))
+    }
+
+    /// Lists the VINs added and removed in `cars` compared to this cache.
+    pub fn diff(&self, cars: &VehiclesList) -> CacheDiff {
+        CacheDiff {
+            added: cars.vehicles.iter()
+                .filter(|c| !self.contains(&c.vin))
+                .map(|c| c.vin.to_owned())
+                .collect(),
+            removed: self.cars.vehicles.iter()
+                .filter(|c| !cars.vehicles.iter().any(|n| n.vin.eq(&c.vin)))
+                .map(|c| c.vin.to_owned())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psa::model::VehiclesListElement;
+    use crate::testutil::car;
+    use std::{env, fs, process};
+
+    fn list(vins: &[&str]) -> VehiclesList {
+        VehiclesList { vehicles: vins.iter().map(|vin| VehiclesListElement { vin: vin.to_string(), ..car() }).collect() }
+    }
+
+    fn temp_file(name: &str) -> String {
+        env::temp_dir().join(format!("cars-cache-{}-{}.yml", process::id(), name)).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let mut cache = CarsCache::new(list(&["A"]));
+        assert!(!cache.is_expired(Duration::hours(1)));
+        cache.fetched_at = Some(Utc::now() - Duration::hours(2));
+        assert!(cache.is_expired(Duration::hours(1)));
+        assert!(!cache.is_expired(Duration::hours(3)));
+        assert!(!cache.is_expired(Duration::max_value()));
+        cache.fetched_at = None;
+        assert!(cache.is_expired(Duration::max_value()));
+    }
+
+    #[test]
+    fn lists_added_and_removed_vehicles() {
+        let cache = CarsCache::new(list(&["A", "B"]));
+        let diff = cache.diff(&list(&["B", "C", "D"]));
+        assert_eq!(diff.added, vec!["C", "D"]);
+        assert_eq!(diff.removed, vec!["A"]);
+        let diff = cache.diff(&list(&["A", "B"]));
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+    }
+
+    #[test]
+    fn loads_saved_cache() {
+        let filename = temp_file("saved");
+        assert!(CarsCache::load(&filename).unwrap().is_none());
+        let cache = CarsCache::new(list(&["A"]));
+        cache.save(&filename).unwrap();
+        let loaded = CarsCache::load(&filename).unwrap().unwrap();
+        fs::remove_file(&filename).unwrap();
+        assert_eq!(loaded.fetched_at, cache.fetched_at);
+        assert!(loaded.contains("A"));
+    }
+
+    #[test]
+    fn reports_corrupt_file() {
+        let filename = temp_file("corrupt");
+        fs::write(&filename, "vehicles: [unterminated").unwrap();
+        let err = CarsCache::load(&filename).unwrap_err();
+        fs::remove_file(&filename).unwrap();
+        assert!(err.to_string().contains("is corrupt"));
+    }
+}
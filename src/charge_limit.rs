@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, error::Error};
+
+use crate::psa::api::ApiClient;
+use crate::psa::model::{RemoteAction, VehicleStatus, VehiclesListElement};
+use crate::watch::WatchHandler;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChargeLimitConfig {
+    // only log the stop commands that would be sent
+    #[serde(default)]
+    pub dry_run: bool,
+    // limits by VIN
+    #[serde(default)]
+    pub vehicles: BTreeMap<String, ChargeLimit>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChargeLimit {
+    // SoC in percent at which charging is stopped
+    pub target: u32,
+    // percent the SoC has to drop below the target before the limit applies again
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: u32,
+}
+
+fn default_hysteresis() -> u32 {
+    5
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LimitState {
+    Armed,
+    // stop was sent, confirmed once the vehicle reported not charging
+    Stopped { confirmed: bool },
+    // dry run logged the stop, the vehicle keeps charging until it stops by itself
+    WouldStop,
+    // charging was restarted manually after the stop
+    Overridden,
+}
+
+/// Stops charging when a vehicle reaches its target SoC. Charging restarted
+/// after a stop is treated as manual override until the vehicle is unplugged
+/// or the SoC dropped below the hysteresis.
+pub struct ChargeLimiter {
+    config: ChargeLimitConfig,
+    states: BTreeMap<String, LimitState>,
+}
+
+impl ChargeLimiter {
+    pub fn new(config: &ChargeLimitConfig, dry_run: bool) -> ChargeLimiter {
+        let mut config = config.clone();
+        config.dry_run |= dry_run;
+        ChargeLimiter {
+            config,
+            states: BTreeMap::new(),
+        }
+    }
+
+    fn stop(&self, client: &mut ApiClient, car: &VehiclesListElement, soc: u32, limit: &ChargeLimit) -> Result<(), Box<dyn Error>> {
+        if self.config.dry_run {
+            println!("{} would stop charging at {}% (target {}%)", car.vin, soc, limit.target);
+            return Ok(());
+        }
+        println!("{} stopping charging at {}% (target {}%)", car.vin, soc, limit.target);
+        client.connectedcar_remote_action(&car.id, RemoteAction::StopCharging)?;
+        Ok(())
+    }
+}
+
+impl WatchHandler for ChargeLimiter {
+    fn on_status(&mut self, client: &mut ApiClient, car: &VehiclesListElement, status: &VehicleStatus) -> Result<(), Box<dyn Error>> {
+        let limit = match self.config.vehicles.get(&car.vin) {
+            Some(limit) => limit.clone(),
+            None => return Ok(()),
+        };
+        let soc = match status.soc() {
+            Some(soc) => soc,
+            None => return Ok(()),
+        };
+        let charging = status.is_charging();
+        let rearm = !status.is_plugged() || soc + limit.hysteresis < limit.target;
+
+        let state = self.states.get(&car.vin).copied().unwrap_or(LimitState::Armed);
+        let next = match state {
+            _ if rearm => LimitState::Armed,
+            LimitState::Armed if charging && soc >= limit.target => {
+                self.stop(client, car, soc, &limit)?;
+                if self.config.dry_run { LimitState::WouldStop } else { LimitState::Stopped { confirmed: false } }
+            },
+            LimitState::Stopped { confirmed: false } if charging => {
+                // stop not applied yet
+                self.stop(client, car, soc, &limit)?;
+                state
+            },
+            // logged again when charging resumes
+            LimitState::WouldStop if !charging => LimitState::Armed,
+            LimitState::Stopped { .. } if !charging => LimitState::Stopped { confirmed: true },
+            LimitState::Stopped { confirmed: true } => {
+                println!("{} charging restarted at {}%, limit overridden", car.vin, soc);
+                LimitState::Overridden
+            },
+            other => other,
+        };
+        self.states.insert(car.vin.to_owned(), next);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, car, http_server, start, VIN};
+    use chrono::Duration;
+
+    fn status(minutes: i64, soc: u32, charging: bool) -> VehicleStatus {
+        let mut status = testutil::status(start() + Duration::minutes(minutes));
+        status.energy[0].level = soc;
+        status.energy[0].extension.as_mut().unwrap().electric.charging.status =
+            if charging { "InProgress" } else { "Stopped" }.to_owned();
+        status
+    }
+
+    fn limiter(dry_run: bool) -> ChargeLimiter {
+        let config = ChargeLimitConfig {
+            dry_run: false,
+            vehicles: BTreeMap::from([(VIN.to_owned(), ChargeLimit { target: 80, hysteresis: default_hysteresis() })]),
+        };
+        ChargeLimiter::new(&config, dry_run)
+    }
+
+    #[test]
+    fn stops_at_target_until_confirmed_then_allows_override() {
+        let (url, requests) = http_server(|_| (200, "{\"remoteActionId\": \"1\"}".to_owned()));
+        let api = testutil::api_config(&url);
+        let mut client = ApiClient::new(&api);
+        let mut limiter = limiter(false);
+
+        let mut states = vec![];
+        for (minutes, soc, charging) in [(0, 70, true), (10, 80, true), (20, 81, true), (30, 81, false), (40, 81, true), (50, 82, true)] {
+            limiter.on_status(&mut client, &car(), &status(minutes, soc, charging)).unwrap();
+            states.push(limiter.states[VIN]);
+        }
+        assert_eq!(states, vec![
+            LimitState::Armed,
+            LimitState::Stopped { confirmed: false },
+            LimitState::Stopped { confirmed: false },
+            LimitState::Stopped { confirmed: true },
+            LimitState::Overridden,
+            LimitState::Overridden,
+        ]);
+        // sent at the target and again while not applied
+        for _ in 0..2 {
+            let request = requests.recv().unwrap();
+            assert!(request.path.starts_with("/connectedcar/v4/user/vehicles/car-id/callbacks/callback/remotes"));
+        }
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn dry_run_logs_stop_once_per_charge() {
+        let api = testutil::api_config("http://127.0.0.1:1");
+        let mut client = ApiClient::new(&api);
+        let mut limiter = limiter(true);
+
+        let mut states = vec![];
+        for (minutes, soc, charging) in [(0, 80, true), (10, 85, true), (20, 90, false), (30, 90, true)] {
+            limiter.on_status(&mut client, &car(), &status(minutes, soc, charging)).unwrap();
+            states.push(limiter.states[VIN]);
+        }
+        // no request is sent, the unreachable api would fail the handler
+        assert_eq!(states, vec![LimitState::WouldStop, LimitState::WouldStop, LimitState::Armed, LimitState::WouldStop]);
+    }
+
+    #[test]
+    fn rearms_when_unplugged_or_below_hysteresis() {
+        let api = testutil::api_config("http://127.0.0.1:1");
+        let mut client = ApiClient::new(&api);
+        let mut limiter = limiter(true);
+
+        limiter.on_status(&mut client, &car(), &status(0, 80, true)).unwrap();
+        let mut unplugged = status(10, 79, false);
+        unplugged.energy[0].extension.as_mut().unwrap().electric.charging.plugged = false;
+        limiter.on_status(&mut client, &car(), &unplugged).unwrap();
+        assert_eq!(limiter.states[VIN], LimitState::Armed);
+
+        limiter.states.insert(VIN.to_owned(), LimitState::Overridden);
+        limiter.on_status(&mut client, &car(), &status(20, 74, true)).unwrap();
+        assert_eq!(limiter.states[VIN], LimitState::Armed);
+    }
+}
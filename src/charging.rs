@@ -0,0 +1,264 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Row};
+use serde::Serialize;
+use std::error::Error;
+
+use crate::history::{HistoryDb, Snapshot};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS charging_sessions (
+    id INTEGER PRIMARY KEY,
+    vin TEXT NOT NULL,
+    start_time TEXT NOT NULL,
+    end_time TEXT NOT NULL,
+    start_soc INTEGER NOT NULL,
+    end_soc INTEGER NOT NULL,
+    capacity_kwh REAL,
+    latitude REAL,
+    longitude REAL,
+    mode TEXT,
+    estimated INTEGER NOT NULL DEFAULT 0,
+    UNIQUE (vin, end_time)
+);
+";
+
+const COLUMNS: &str = "id, vin, start_time, end_time, start_soc, end_soc, capacity_kwh, latitude, longitude, mode, estimated";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChargingSession {
+    pub id: Option<i64>,
+    pub vin: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub start_soc: u32,
+    pub end_soc: u32,
+    pub capacity_kwh: Option<f64>,
+    pub position: Option<(f64, f64)>,
+    // charging mode reported by the car, Slow or Quick
+    pub mode: Option<String>,
+    // missed by the polling, only known to be somewhere between start and end
+    pub estimated: bool,
+}
+
+impl ChargingSession {
+    fn between(start: &Snapshot, end: &Snapshot, mode: Option<String>, estimated: bool) -> Option<ChargingSession> {
+        Some(ChargingSession {
+            id: None,
+            vin: start.vin.to_owned(),
+            start_time: start.updated_at,
+            end_time: end.updated_at,
+            start_soc: start.soc?,
+            end_soc: end.soc?,
+            capacity_kwh: end.battery_capacity_kwh().or(start.battery_capacity_kwh()),
+            position: start.position().or(end.position()),
+            mode,
+            estimated,
+        })
+    }
+
+    pub fn energy_kwh(&self) -> Option<f64> {
+        self.capacity_kwh.map(|c| (self.end_soc as f64 - self.start_soc as f64) / 100.0 * c)
+    }
+
+    pub fn duration(&self) -> chrono::Duration {
+        self.end_time - self.start_time
+    }
+
+    pub fn average_power_kw(&self) -> Option<f64> {
+        let hours = self.duration().num_seconds() as f64 / 3600.0;
+        if hours > 0.0 {
+            self.energy_kwh().map(|e| e / hours)
+        } else {
+            None
+        }
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<ChargingSession> {
+        let latitude: Option<f64> = row.get(7)?;
+        let longitude: Option<f64> = row.get(8)?;
+        Ok(ChargingSession {
+            id: row.get(0)?,
+            vin: row.get(1)?,
+            start_time: row.get(2)?,
+            end_time: row.get(3)?,
+            start_soc: row.get(4)?,
+            end_soc: row.get(5)?,
+            capacity_kwh: row.get(6)?,
+            position: latitude.zip(longitude),
+            mode: row.get(9)?,
+            estimated: row.get(10)?,
+        })
+    }
+}
+
+/// Splits the snapshots of one vehicle, ordered by time, into charging
+/// sessions.
+///
+/// A session starts with the last plugged snapshot before the charging
+/// status turned `InProgress` and ends with the first snapshot not charging
+/// anymore. A status change to `Finished` between two plugged snapshots with
+/// a SoC increase is a session the polling did not catch. Sessions still in
+/// progress are not returned.
+pub fn detect_sessions(snapshots: &[Snapshot]) -> Vec<ChargingSession> {
+    let mut sessions = vec![];
+    let mut session_start: Option<&Snapshot> = None;
+    let mut mode: Option<String> = None;
+    let mut previous: Option<&Snapshot> = None;
+
+    for snapshot in snapshots {
+        let charging = snapshot.charging_status.as_deref() == Some("InProgress");
+        match (session_start, charging) {
+            (None, true) => {
+                session_start = Some(previous.filter(|p| p.plugged).unwrap_or(snapshot));
+                mode = None;
+            },
+            (Some(start), false) => {
+                sessions.extend(ChargingSession::between(start, snapshot, mode.take(), false));
+                session_start = None;
+            },
+            (None, false) => {
+                // SoC changes without a status change are measurement noise
+                let finished = |s: &Snapshot| s.charging_status.as_deref() == Some("Finished");
+                if let Some(previous) = previous.filter(|p| p.plugged && snapshot.plugged && !finished(p) && finished(snapshot)) {
+                    if snapshot.soc > previous.soc {
+                        sessions.extend(ChargingSession::between(previous, snapshot, snapshot.charging_mode.clone(), true));
+                    }
+                }
+            },
+            (Some(_), true) => (),
+        }
+        if charging && mode.is_none() {
+            mode = snapshot.charging_mode.clone().filter(|m| !m.eq("No"));
+        }
+        previous = Some(snapshot);
+    }
+
+    sessions.into_iter().filter(|s| s.end_soc > s.start_soc).collect()
+}
+
+/// Stores the sessions keyed by their end, which does not depend on how far
+/// back the history was read. A known session is only updated if it now
+/// starts earlier. Returns the number of new sessions.
+pub fn save_sessions(db: &HistoryDb, sessions: &[ChargingSession]) -> Result<usize, Box<dyn Error>> {
+    let conn = db.connection();
+    conn.execute_batch(SCHEMA)?;
+    let mut inserted = 0;
+    for s in sessions {
+        let known: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM charging_sessions WHERE vin = ?1 AND end_time = ?2)",
+            params![s.vin, s.end_time], |row| row.get(0))?;
+        conn.execute(
+            "INSERT INTO charging_sessions (vin, start_time, end_time, start_soc, end_soc, capacity_kwh, latitude, longitude, mode, estimated) \
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10) \
+                ON CONFLICT (vin, end_time) DO UPDATE SET start_time = excluded.start_time, start_soc = excluded.start_soc, \
+                    latitude = excluded.latitude, longitude = excluded.longitude, mode = coalesce(mode, excluded.mode), \
+                    estimated = excluded.estimated \
+                WHERE excluded.start_time < start_time",
+            params![s.vin, s.start_time, s.end_time, s.start_soc, s.end_soc, s.capacity_kwh,
+                s.position.map(|p| p.0), s.position.map(|p| p.1), s.mode, s.estimated],
+        )?;
+        if !known {
+            inserted += 1;
+        }
+    }
+    Ok(inserted)
+}
+
+/// Stored sessions of the vehicle starting within `[from, to]`.
+pub fn load_sessions(db: &HistoryDb, vin: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<ChargingSession>, Box<dyn Error>> {
+    let conn = db.connection();
+    conn.execute_batch(SCHEMA)?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM charging_sessions WHERE vin = ?1 AND start_time >= ?2 AND start_time <= ?3 ORDER BY start_time", COLUMNS))?;
+    let rows = stmt.query_map(params![vin, from, to], ChargingSession::from_row)?;
+    Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{snapshot, start, VIN};
+    use chrono::Duration;
+
+    fn plugged(minutes: i64, soc: u32, status: &str) -> Snapshot {
+        Snapshot {
+            plugged: true,
+            charging_status: Some(status.to_owned()),
+            charging_mode: Some(if status == "InProgress" { "Slow" } else { "No" }.to_owned()),
+            ..snapshot(minutes, 100.0, soc)
+        }
+    }
+
+    #[test]
+    fn detects_session_from_last_plugged_snapshot_to_end_of_charging() {
+        let snapshots = vec![
+            snapshot(0, 100.0, 40),
+            plugged(10, 40, "Stopped"),
+            plugged(20, 45, "InProgress"),
+            plugged(30, 60, "InProgress"),
+            plugged(40, 70, "Finished"),
+            plugged(50, 70, "Finished"),
+        ];
+        let sessions = detect_sessions(&snapshots);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].start_time, start() + Duration::minutes(10));
+        assert_eq!(sessions[0].end_time, start() + Duration::minutes(40));
+        assert_eq!((sessions[0].start_soc, sessions[0].end_soc), (40, 70));
+        assert_eq!(sessions[0].mode.as_deref(), Some("Slow"));
+        assert_eq!(sessions[0].energy_kwh(), Some(0.3 * 46.0));
+        assert!(!sessions[0].estimated);
+    }
+
+    #[test]
+    fn ignores_soc_noise_without_status_change() {
+        let snapshots = vec![
+            plugged(0, 79, "Finished"),
+            plugged(10, 80, "Finished"),
+            plugged(20, 79, "Finished"),
+            plugged(30, 80, "Finished"),
+            plugged(40, 81, "Stopped"),
+        ];
+        assert!(detect_sessions(&snapshots).is_empty());
+    }
+
+    #[test]
+    fn detects_session_missed_by_polling() {
+        let snapshots = vec![
+            plugged(0, 40, "Stopped"),
+            plugged(120, 80, "Finished"),
+        ];
+        let sessions = detect_sessions(&snapshots);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!((sessions[0].start_soc, sessions[0].end_soc), (40, 80));
+        assert!(sessions[0].estimated);
+    }
+
+    #[test]
+    fn skips_session_in_progress() {
+        let snapshots = vec![
+            plugged(0, 40, "Stopped"),
+            plugged(10, 45, "InProgress"),
+        ];
+        assert!(detect_sessions(&snapshots).is_empty());
+    }
+
+    #[test]
+    fn dedups_sessions_on_their_end_when_start_shifts() {
+        let db = HistoryDb::open(":memory:").unwrap();
+        let snapshots = vec![
+            plugged(0, 40, "Stopped"),
+            plugged(10, 45, "InProgress"),
+            plugged(20, 60, "InProgress"),
+            plugged(30, 70, "Finished"),
+        ];
+        // a shorter window only sees the session from its second snapshot
+        assert_eq!(save_sessions(&db, &detect_sessions(&snapshots[1..])).unwrap(), 1);
+        assert_eq!(save_sessions(&db, &detect_sessions(&snapshots)).unwrap(), 0);
+        assert_eq!(save_sessions(&db, &detect_sessions(&snapshots[1..])).unwrap(), 0);
+
+        let stored = load_sessions(&db, VIN, start(), start() + Duration::hours(1)).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].start_time, start());
+        assert_eq!((stored[0].start_soc, stored[0].end_soc), (40, 70));
+    }
+}
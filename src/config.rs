@@ -1,13 +1,40 @@
+use chrono::Duration;
 use serde::{Deserialize, Serialize};
-use std::{fs::{File, OpenOptions}, cell::RefCell};
+use std::{fs::{File, OpenOptions}, cell::RefCell, collections::BTreeMap, io::Read};
 
 use crate::psa::model::ApiConfig;
+use crate::watch::WatchConfig;
+use crate::mqtt::MqttConfig;
+use crate::tariff::TariffConfig;
+use crate::notify::NotifyConfig;
+use crate::server::ServeConfig;
+use crate::influx::InfluxConfig;
+use crate::abrp::AbrpConfig;
+use crate::smart_charge::SmartChargeConfig;
+use crate::preconditioning::PreconditioningConfig;
 
 pub trait YamlConfigFile<T> {
     fn from_file(filename: String) -> Result<T, Box<dyn std::error::Error>>;
     fn to_file(&self, filename: String) -> Result<(), Box<dyn std::error::Error>>;
 }
 
+// profile name used for configs written before profiles existed
+pub const LEGACY_PROFILE: &str = "default";
+const LEGACY_CARS_FILE: &str = "cars.yaml";
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub message: String
+}
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Config Error: {}", self.message)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppConfig {
     pub api: RefCell<ApiConfig>,
@@ -18,6 +45,15 @@ pub struct AppConfig {
     pub culture: String,
     pub brand_code: String,
     pub customer_id: String,
+    #[serde(default)]
+    pub cars_file: String,
+    // hours until the cached vehicle list is fetched again
+    #[serde(default = "default_cars_ttl")]
+    pub cars_ttl: u64,
+}
+
+fn default_cars_ttl() -> u64 {
+    24
 }
 
 impl Default for AppConfig {
@@ -31,24 +67,141 @@ impl Default for AppConfig {
             culture: "".to_string(),
             brand_code: "".to_string(),
             customer_id: "".to_string(),
+            cars_file: "".to_string(),
+            cars_ttl: default_cars_ttl(),
+        }
+    }
+}
+
+impl AppConfig {
+    pub fn cars_file(&self, profile: &str) -> String {
+        if self.cars_file.is_empty() {
+            format!("cars-{}.yaml", profile)
+        } else {
+            self.cars_file.to_owned()
         }
     }
+
+    /// Cache lifetime, TTLs beyond the range of `Duration` never expire.
+    pub fn cars_ttl(&self) -> Duration {
+        i64::try_from(self.cars_ttl).ok()
+            .and_then(Duration::try_hours)
+            .unwrap_or_else(Duration::max_value)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, AppConfig>,
+    #[serde(default)]
+    pub watch: WatchConfig,
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default = "default_history_db")]
+    pub history_db: String,
+    #[serde(default)]
+    pub tariffs: Option<TariffConfig>,
+    #[serde(default)]
+    pub notifications: Option<NotifyConfig>,
+    #[serde(default)]
+    pub serve: Option<ServeConfig>,
+    #[serde(default)]
+    pub influx: Option<InfluxConfig>,
+    #[serde(default)]
+    pub abrp: Option<AbrpConfig>,
+    #[serde(default)]
+    pub smart_charging: Option<SmartChargeConfig>,
+    #[serde(default)]
+    pub preconditioning: Option<PreconditioningConfig>,
+    // secret of the logbook hashes, generated on first use
+    #[serde(default)]
+    pub logbook_key: Option<String>,
+}
+
+fn default_history_db() -> String {
+    "history.sqlite".to_owned()
 }
 
-impl YamlConfigFile<AppConfig> for AppConfig {
-    fn from_file(filename: String) -> Result<AppConfig, Box<dyn std::error::Error>> {
+impl Default for ConfigFile {
+    fn default() -> Self {
+        ConfigFile {
+            default_profile: None,
+            profiles: BTreeMap::new(),
+            watch: WatchConfig::default(),
+            mqtt: None,
+            history_db: default_history_db(),
+            tariffs: None,
+            notifications: None,
+            serve: None,
+            influx: None,
+            abrp: None,
+            smart_charging: None,
+            preconditioning: None,
+            logbook_key: None,
+        }
+    }
+}
+
+impl ConfigFile {
+    /// Checks the values serde can not, e.g. ranges.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.watch.health.validate().map_err(|message| ConfigError { message })?;
+        if let Some(preconditioning) = &self.preconditioning {
+            preconditioning.planner.validate().map_err(|message| ConfigError { message })?;
+        }
+        Ok(())
+    }
+
+    /// Resolves the profile to use: the requested one, the configured default
+    /// or the only existing profile.
+    pub fn select_profile(&self, requested: Option<&str>) -> Result<String, ConfigError> {
+        if let Some(name) = requested.or(self.default_profile.as_deref()) {
+            return Ok(name.to_owned());
+        }
+        match self.profiles.len() {
+            0 => Ok(LEGACY_PROFILE.to_owned()),
+            1 => Ok(self.profiles.keys().next().unwrap().to_owned()),
+            _ => Err(ConfigError { message: format!("Multiple profiles configured, select one of: {}",
+                self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")) }),
+        }
+    }
+}
+
+impl YamlConfigFile<ConfigFile> for ConfigFile {
+    fn from_file(filename: String) -> Result<ConfigFile, Box<dyn std::error::Error>> {
+        let mut content = String::new();
         match File::open(filename) {
-            Ok(f) => {
-                let cfg: AppConfig = serde_yaml::from_reader(f)?;
-                return Ok(cfg)
-            },
-            Err(_) => return Ok(AppConfig::default()),
+            Ok(mut f) => f.read_to_string(&mut content)?,
+            Err(_) => return Ok(ConfigFile::default()),
         };
+        if content.trim().is_empty() {
+            return Ok(ConfigFile::default());
+        }
+
+        let value: serde_yaml::Value = serde_yaml::from_str(&content)?;
+        // only single brand configs have a top level api section, a profile
+        // config may not have any profiles yet
+        if value.get("api").is_none() {
+            let cfg: ConfigFile = serde_yaml::from_value(value)?;
+            cfg.validate()?;
+            return Ok(cfg);
+        }
+
+        // migrate single brand config into a profile
+        let mut cfg: AppConfig = serde_yaml::from_value(value)?;
+        cfg.cars_file = LEGACY_CARS_FILE.to_owned();
+        Ok(ConfigFile {
+            profiles: BTreeMap::from([(LEGACY_PROFILE.to_owned(), cfg)]),
+            ..Default::default()
+        })
     }
 
     fn to_file(&self, filename: String) -> Result<(), Box<dyn std::error::Error>> {
-        let f = OpenOptions::new().write(true).create(true).open(filename)?;
+        let f = OpenOptions::new().write(true).create(true).truncate(true).open(filename)?;
         serde_yaml::to_writer(f, &self)?;
         Ok(())
     }
-}
\ No newline at end of file
+}
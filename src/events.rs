@@ -0,0 +1,197 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::geo;
+use crate::health::HealthWarning;
+use crate::psa::model::VehicleStatus;
+
+// position changes below this are treated as GPS noise
+const MIN_MOVE_DISTANCE_M: f64 = 100.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VehicleEventKind {
+    PluggedIn,
+    Unplugged,
+    ChargingStarted,
+    ChargeFinished,
+    ChargingStopped,
+    IgnitionOn,
+    IgnitionOff,
+    Moved { distance_km: f32 },
+    SocThresholdCrossed { threshold: u32, rising: bool, level: u32 },
+    GeofenceEntered { geofence: String },
+    GeofenceLeft { geofence: String },
+    HealthWarning { warning: HealthWarning },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VehicleEvent {
+    pub vin: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: VehicleEventKind,
+}
+
+impl VehicleEventKind {
+    /// Type name of the event as used in the serialized form.
+    pub fn name(&self) -> &'static str {
+        match self {
+            VehicleEventKind::PluggedIn => "plugged_in",
+            VehicleEventKind::Unplugged => "unplugged",
+            VehicleEventKind::ChargingStarted => "charging_started",
+            VehicleEventKind::ChargeFinished => "charge_finished",
+            VehicleEventKind::ChargingStopped => "charging_stopped",
+            VehicleEventKind::IgnitionOn => "ignition_on",
+            VehicleEventKind::IgnitionOff => "ignition_off",
+            VehicleEventKind::Moved { .. } => "moved",
+            VehicleEventKind::SocThresholdCrossed { .. } => "soc_threshold_crossed",
+            VehicleEventKind::GeofenceEntered { .. } => "geofence_entered",
+            VehicleEventKind::GeofenceLeft { .. } => "geofence_left",
+            VehicleEventKind::HealthWarning { .. } => "health_warning",
+        }
+    }
+}
+
+impl std::fmt::Display for VehicleEventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VehicleEventKind::PluggedIn => write!(f, "plugged in"),
+            VehicleEventKind::Unplugged => write!(f, "unplugged"),
+            VehicleEventKind::ChargingStarted => write!(f, "charging started"),
+            VehicleEventKind::ChargeFinished => write!(f, "charge finished"),
+            VehicleEventKind::ChargingStopped => write!(f, "charging stopped"),
+            VehicleEventKind::IgnitionOn => write!(f, "ignition on"),
+            VehicleEventKind::IgnitionOff => write!(f, "ignition off"),
+            VehicleEventKind::Moved { distance_km } => write!(f, "moved {:.1} km", distance_km),
+            VehicleEventKind::SocThresholdCrossed { threshold, rising, level } =>
+                write!(f, "SoC {} {}% (now {}%)", if *rising { "rose above" } else { "fell below" }, threshold, level),
+            VehicleEventKind::GeofenceEntered { geofence } => write!(f, "entered {}", geofence),
+            VehicleEventKind::GeofenceLeft { geofence } => write!(f, "left {}", geofence),
+            VehicleEventKind::HealthWarning { warning } => write!(f, "warning: {}", warning),
+        }
+    }
+}
+
+/// Compares two consecutive status snapshots of a vehicle and returns the
+/// changes as events.
+pub fn diff_status(vin: &str, previous: &VehicleStatus, current: &VehicleStatus, soc_thresholds: &[u32]) -> Vec<VehicleEvent> {
+    let mut kinds = vec![];
+
+    match (previous.is_plugged(), current.is_plugged()) {
+        (false, true) => kinds.push(VehicleEventKind::PluggedIn),
+        (true, false) => kinds.push(VehicleEventKind::Unplugged),
+        _ => (),
+    }
+
+    match (previous.is_charging(), current.is_charging()) {
+        (false, true) => kinds.push(VehicleEventKind::ChargingStarted),
+        (true, false) => {
+            if current.charging().is_some_and(|c| c.status.eq("Finished")) {
+                kinds.push(VehicleEventKind::ChargeFinished);
+            } else {
+                kinds.push(VehicleEventKind::ChargingStopped);
+            }
+        },
+        _ => (),
+    }
+
+    match (previous.ignition_on(), current.ignition_on()) {
+        (false, true) => kinds.push(VehicleEventKind::IgnitionOn),
+        (true, false) => kinds.push(VehicleEventKind::IgnitionOff),
+        _ => (),
+    }
+
+    let distance_km = current.odometer.mileage - previous.odometer.mileage;
+    let moved_position = match (previous.position(), current.position()) {
+        (Some(a), Some(b)) => geo::distance_m(a, b) > MIN_MOVE_DISTANCE_M,
+        _ => false,
+    };
+    if distance_km > 0.0 || moved_position {
+        kinds.push(VehicleEventKind::Moved { distance_km: distance_km.max(0.0) });
+    }
+
+    if let (Some(before), Some(level)) = (previous.soc(), current.soc()) {
+        for &threshold in soc_thresholds {
+            if before < threshold && level >= threshold {
+                kinds.push(VehicleEventKind::SocThresholdCrossed { threshold, rising: true, level });
+            } else if before >= threshold && level < threshold {
+                kinds.push(VehicleEventKind::SocThresholdCrossed { threshold, rising: false, level });
+            }
+        }
+    }
+
+    kinds.into_iter().map(|kind| VehicleEvent {
+        vin: vin.to_owned(),
+        timestamp: current.updated_at,
+        kind,
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{start, status, VIN};
+
+    fn parked() -> VehicleStatus {
+        status(start())
+    }
+
+    fn with(change: impl FnOnce(&mut VehicleStatus)) -> VehicleStatus {
+        let mut status = status(start() + chrono::Duration::minutes(10));
+        change(&mut status);
+        status
+    }
+
+    fn charging(plugged: bool, state: &str) -> VehicleStatus {
+        with(|s| {
+            let charging = &mut s.energy[0].extension.as_mut().unwrap().electric.charging;
+            charging.plugged = plugged;
+            charging.status = state.to_owned();
+        })
+    }
+
+    fn soc(level: u32) -> VehicleStatus {
+        with(|s| s.energy[0].level = level)
+    }
+
+    #[test]
+    fn reports_changes_between_statuses() {
+        let moved = |s: &mut VehicleStatus, lat_offset: f32| s.last_position.geometry.coordinates[1] += lat_offset;
+        let cases = vec![
+            ("unchanged", parked(), with(|_| ()), vec![]),
+            ("plugged in", charging(false, "Disconnected"), charging(true, "Stopped"), vec![VehicleEventKind::PluggedIn]),
+            ("unplugged", parked(), charging(false, "Disconnected"), vec![VehicleEventKind::Unplugged]),
+            ("charging started", parked(), charging(true, "InProgress"), vec![VehicleEventKind::ChargingStarted]),
+            ("charge finished", charging(true, "InProgress"), charging(true, "Finished"), vec![VehicleEventKind::ChargeFinished]),
+            ("charging stopped", charging(true, "InProgress"), charging(true, "Stopped"), vec![VehicleEventKind::ChargingStopped]),
+            ("unplugged while charging", charging(true, "InProgress"), charging(false, "Disconnected"),
+                vec![VehicleEventKind::Unplugged, VehicleEventKind::ChargingStopped]),
+            ("ignition on", parked(), with(|s| s.ignition._type = "Start".to_owned()), vec![VehicleEventKind::IgnitionOn]),
+            ("ignition off", with(|s| s.ignition._type = "Start".to_owned()), parked(), vec![VehicleEventKind::IgnitionOff]),
+            ("odometer", with(|s| s.odometer.mileage = 12000.0), with(|s| s.odometer.mileage = 12012.5),
+                vec![VehicleEventKind::Moved { distance_km: 12.5 }]),
+            // about 1.1 km north
+            ("position", parked(), with(|s| moved(s, 0.01)), vec![VehicleEventKind::Moved { distance_km: 0.0 }]),
+            ("gps noise", parked(), with(|s| moved(s, 0.0005)), vec![]),
+            ("soc rising", soc(45), soc(85), vec![
+                VehicleEventKind::SocThresholdCrossed { threshold: 50, rising: true, level: 85 },
+                VehicleEventKind::SocThresholdCrossed { threshold: 80, rising: true, level: 85 },
+            ]),
+            ("soc reaching threshold", soc(79), soc(80), vec![VehicleEventKind::SocThresholdCrossed { threshold: 80, rising: true, level: 80 }]),
+            ("soc falling", soc(85), soc(15), vec![
+                VehicleEventKind::SocThresholdCrossed { threshold: 20, rising: false, level: 15 },
+                VehicleEventKind::SocThresholdCrossed { threshold: 50, rising: false, level: 15 },
+                VehicleEventKind::SocThresholdCrossed { threshold: 80, rising: false, level: 15 },
+            ]),
+            ("soc leaving threshold", soc(80), soc(79), vec![VehicleEventKind::SocThresholdCrossed { threshold: 80, rising: false, level: 79 }]),
+            ("soc within band", soc(55), soc(75), vec![]),
+        ];
+
+        for (name, previous, current, expected) in cases {
+            let events = diff_status(VIN, &previous, &current, &[20, 50, 80]);
+            assert_eq!(events.iter().map(|e| e.kind.clone()).collect::<Vec<_>>(), expected, "{}", name);
+            assert!(events.iter().all(|e| e.vin == VIN && e.timestamp == current.updated_at), "{}", name);
+        }
+    }
+}
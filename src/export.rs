@@ -0,0 +1,278 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::json;
+use std::{error::Error, fmt, io::Write, str::FromStr};
+
+use crate::history::Snapshot;
+use crate::trips::Trip;
+
+#[derive(Debug)]
+pub struct ExportError {
+    pub message: String
+}
+
+impl Error for ExportError {}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Export Error: {}", self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeoFormat {
+    GeoJson,
+    Gpx,
+    Kml,
+}
+
+impl FromStr for GeoFormat {
+    type Err = ExportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "geojson" => Ok(GeoFormat::GeoJson),
+            "gpx" => Ok(GeoFormat::Gpx),
+            "kml" => Ok(GeoFormat::Kml),
+            _ => Err(ExportError { message: format!("Unknown format {}, use geojson, gpx or kml", s) }),
+        }
+    }
+}
+
+/// What to export: the live position, the recorded positions or the trips.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportKind {
+    Position,
+    History,
+    Trips,
+}
+
+impl FromStr for ExportKind {
+    type Err = ExportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "position" => Ok(ExportKind::Position),
+            "history" => Ok(ExportKind::History),
+            "trips" => Ok(ExportKind::Trips),
+            _ => Err(ExportError { message: format!("Unknown export {}, use position, history or trips", s) }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackPoint {
+    pub time: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Waypoint {
+    pub name: String,
+    pub point: TrackPoint,
+}
+
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub name: String,
+    pub points: Vec<TrackPoint>,
+}
+
+impl TrackPoint {
+    pub fn from_snapshot(snapshot: &Snapshot) -> Option<TrackPoint> {
+        let (latitude, longitude) = snapshot.position()?;
+        Some(TrackPoint { time: snapshot.updated_at, latitude, longitude })
+    }
+}
+
+/// Track of all recorded positions, repeated positions of a parked vehicle
+/// are skipped.
+pub fn history_track(name: &str, snapshots: &[Snapshot]) -> Track {
+    let mut points: Vec<TrackPoint> = vec![];
+    for point in snapshots.iter().filter_map(TrackPoint::from_snapshot) {
+        if !points.last().is_some_and(|p| p.latitude == point.latitude && p.longitude == point.longitude) {
+            points.push(point);
+        }
+    }
+    Track { name: name.to_owned(), points }
+}
+
+/// One track per trip made of the recorded positions during the trip.
+pub fn trip_tracks(trips: &[Trip], snapshots: &[Snapshot]) -> Vec<Track> {
+    trips.iter().map(|trip| Track {
+        name: format!("{} {:.1} km", trip.start_time.format("%Y-%m-%d %H:%M"), trip.distance_km()),
+        points: snapshots.iter()
+            .filter(|s| s.updated_at >= trip.start_time && s.updated_at <= trip.end_time)
+            .filter_map(TrackPoint::from_snapshot)
+            .collect(),
+    }).collect()
+}
+
+fn timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Writes the waypoints and tracks, tracks without any position are left
+/// out in every format.
+pub fn write<W: Write>(w: W, format: GeoFormat, waypoints: &[Waypoint], tracks: &[Track]) -> Result<(), Box<dyn Error>> {
+    let tracks = tracks.iter().filter(|t| !t.points.is_empty()).collect::<Vec<_>>();
+    match format {
+        GeoFormat::GeoJson => write_geojson(w, waypoints, &tracks),
+        GeoFormat::Gpx => write_gpx(w, waypoints, &tracks),
+        GeoFormat::Kml => write_kml(w, waypoints, &tracks),
+    }
+}
+
+fn write_geojson<W: Write>(w: W, waypoints: &[Waypoint], tracks: &[&Track]) -> Result<(), Box<dyn Error>> {
+    let mut features = vec![];
+    for wpt in waypoints {
+        features.push(json!({
+            "type": "Feature",
+            "geometry": { "type": "Point", "coordinates": [wpt.point.longitude, wpt.point.latitude] },
+            "properties": { "name": wpt.name, "time": timestamp(&wpt.point.time) },
+        }));
+    }
+    for track in tracks {
+        features.push(json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "LineString",
+                "coordinates": track.points.iter().map(|p| [p.longitude, p.latitude]).collect::<Vec<_>>(),
+            },
+            "properties": {
+                "name": track.name,
+                "coordTimes": track.points.iter().map(|p| timestamp(&p.time)).collect::<Vec<_>>(),
+            },
+        }));
+    }
+    serde_json::to_writer_pretty(w, &json!({ "type": "FeatureCollection", "features": features }))?;
+    Ok(())
+}
+
+fn write_gpx<W: Write>(mut w: W, waypoints: &[Waypoint], tracks: &[&Track]) -> Result<(), Box<dyn Error>> {
+    writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(w, r#"<gpx version="1.1" creator="stellantis-connected-car" xmlns="http://www.topografix.com/GPX/1/1">"#)?;
+    for wpt in waypoints {
+        writeln!(w, r#"  <wpt lat="{}" lon="{}"><time>{}</time><name>{}</name></wpt>"#,
+            wpt.point.latitude, wpt.point.longitude, timestamp(&wpt.point.time), escape_xml(&wpt.name))?;
+    }
+    for track in tracks {
+        writeln!(w, "  <trk>\n    <name>{}</name>\n    <trkseg>", escape_xml(&track.name))?;
+        for p in &track.points {
+            writeln!(w, r#"      <trkpt lat="{}" lon="{}"><time>{}</time></trkpt>"#, p.latitude, p.longitude, timestamp(&p.time))?;
+        }
+        writeln!(w, "    </trkseg>\n  </trk>")?;
+    }
+    writeln!(w, "</gpx>")?;
+    Ok(())
+}
+
+fn write_kml<W: Write>(mut w: W, waypoints: &[Waypoint], tracks: &[&Track]) -> Result<(), Box<dyn Error>> {
+    writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(w, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#)?;
+    writeln!(w, "<Document>")?;
+    for wpt in waypoints {
+        writeln!(w, "  <Placemark>\n    <name>{}</name>\n    <TimeStamp><when>{}</when></TimeStamp>", escape_xml(&wpt.name), timestamp(&wpt.point.time))?;
+        writeln!(w, "    <Point><coordinates>{},{}</coordinates></Point>\n  </Placemark>", wpt.point.longitude, wpt.point.latitude)?;
+    }
+    for track in tracks {
+        let (Some(first), Some(last)) = (track.points.first(), track.points.last()) else {
+            continue;
+        };
+        writeln!(w, "  <Placemark>\n    <name>{}</name>", escape_xml(&track.name))?;
+        writeln!(w, "    <TimeSpan><begin>{}</begin><end>{}</end></TimeSpan>", timestamp(&first.time), timestamp(&last.time))?;
+        let coordinates = track.points.iter().map(|p| format!("{},{}", p.longitude, p.latitude)).collect::<Vec<_>>().join(" ");
+        writeln!(w, "    <LineString><tessellate>1</tessellate><coordinates>{}</coordinates></LineString>\n  </Placemark>", coordinates)?;
+    }
+    writeln!(w, "</Document>\n</kml>")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{snapshot, start};
+    use chrono::Duration;
+
+    fn point(minutes: i64, latitude: f64, longitude: f64) -> TrackPoint {
+        TrackPoint { time: start() + Duration::minutes(minutes), latitude, longitude }
+    }
+
+    fn export(format: GeoFormat) -> String {
+        let waypoints = [Waypoint { name: "Home & Garage".to_owned(), point: point(0, 48.1, 11.5) }];
+        let tracks = [
+            Track { name: "empty".to_owned(), points: vec![] },
+            Track { name: "<trip>".to_owned(), points: vec![point(0, 48.1, 11.5), point(10, 48.2, 11.6)] },
+        ];
+        let mut out = vec![];
+        write(&mut out, format, &waypoints, &tracks).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_geojson() {
+        let json: serde_json::Value = serde_json::from_str(&export(GeoFormat::GeoJson)).unwrap();
+        let features = json["features"].as_array().unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0]["geometry"]["coordinates"], json!([11.5, 48.1]));
+        assert_eq!(features[0]["properties"]["name"], "Home & Garage");
+        assert_eq!(features[0]["properties"]["time"], "2024-03-01T08:00:00Z");
+        assert_eq!(features[1]["geometry"]["coordinates"], json!([[11.5, 48.1], [11.6, 48.2]]));
+        assert_eq!(features[1]["properties"]["coordTimes"], json!(["2024-03-01T08:00:00Z", "2024-03-01T08:10:00Z"]));
+    }
+
+    #[test]
+    fn writes_gpx() {
+        assert_eq!(export(GeoFormat::Gpx), r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="stellantis-connected-car" xmlns="http://www.topografix.com/GPX/1/1">
+  <wpt lat="48.1" lon="11.5"><time>2024-03-01T08:00:00Z</time><name>Home &amp; Garage</name></wpt>
+  <trk>
+    <name>&lt;trip&gt;</name>
+    <trkseg>
+      <trkpt lat="48.1" lon="11.5"><time>2024-03-01T08:00:00Z</time></trkpt>
+      <trkpt lat="48.2" lon="11.6"><time>2024-03-01T08:10:00Z</time></trkpt>
+    </trkseg>
+  </trk>
+</gpx>
+"#);
+    }
+
+    #[test]
+    fn writes_kml() {
+        assert_eq!(export(GeoFormat::Kml), r#"<?xml version="1.0" encoding="UTF-8"?>
+<kml xmlns="http://www.opengis.net/kml/2.2">
+<Document>
+  <Placemark>
+    <name>Home &amp; Garage</name>
+    <TimeStamp><when>2024-03-01T08:00:00Z</when></TimeStamp>
+    <Point><coordinates>11.5,48.1</coordinates></Point>
+  </Placemark>
+  <Placemark>
+    <name>&lt;trip&gt;</name>
+    <TimeSpan><begin>2024-03-01T08:00:00Z</begin><end>2024-03-01T08:10:00Z</end></TimeSpan>
+    <LineString><tessellate>1</tessellate><coordinates>11.5,48.1 11.6,48.2</coordinates></LineString>
+  </Placemark>
+</Document>
+</kml>
+"#);
+    }
+
+    #[test]
+    fn skips_repeated_positions_in_history() {
+        let mut moved = snapshot(20, 110.0, 70);
+        moved.latitude = Some(48.2);
+        let mut unknown = snapshot(30, 110.0, 70);
+        unknown.latitude = None;
+        let track = history_track("history", &[snapshot(0, 100.0, 80), snapshot(10, 100.0, 80), moved, unknown]);
+        let times = track.points.iter().map(|p| (p.time - start()).num_minutes()).collect::<Vec<_>>();
+        assert_eq!(times, vec![0, 20]);
+    }
+}
@@ -0,0 +1,107 @@
+use serde::Serialize;
+use std::{cell::RefCell, collections::BTreeMap, error::Error, thread};
+
+use crate::psa::api::ApiClient;
+use crate::psa::model::{ApiConfig, VehicleStatus, VehiclesList};
+
+// upper bound of status requests running at the same time
+const MAX_WORKERS: usize = 4;
+
+#[derive(Debug, Serialize)]
+pub struct FleetEntry {
+    pub id: String,
+    pub brand: String,
+    pub status: Option<VehicleStatus>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FleetReport {
+    pub vehicles: BTreeMap<String, FleetEntry>,
+}
+
+impl FleetReport {
+    pub fn failed(&self) -> usize {
+        self.vehicles.values().filter(|e| e.error.is_some()).count()
+    }
+}
+
+/// Fetches the status of every vehicle in the list, a failing vehicle is
+/// recorded in its entry and does not abort the others.
+pub fn fetch_fleet_status(config: &RefCell<ApiConfig>, cars: &VehiclesList) -> Result<FleetReport, Box<dyn Error>> {
+    // refresh the token once, the workers run on copies of the config
+    ApiClient::new(config).token_request()?;
+    let shared = config.borrow().clone();
+
+    let mut report = FleetReport { vehicles: BTreeMap::new() };
+    for chunk in cars.vehicles.chunks(MAX_WORKERS) {
+        let results = thread::scope(|s| {
+            let handles: Vec<_> = chunk.iter().map(|car| {
+                let worker_config = shared.clone();
+                s.spawn(move || {
+                    let cell = RefCell::new(worker_config);
+                    let mut client = ApiClient::new(&cell);
+                    client.connectedcar_get_vehicle_status(&car.id).map_err(|e| e.to_string())
+                })
+            }).collect();
+
+            handles.into_iter()
+                .map(|h| h.join().unwrap_or_else(|_| Err("status worker panicked".to_owned())))
+                .collect::<Vec<_>>()
+        });
+
+        for (car, result) in chunk.iter().zip(results) {
+            let (status, error) = match result {
+                Ok(status) => (Some(status), None),
+                Err(e) => (None, Some(e)),
+            };
+            report.vehicles.insert(car.vin.to_owned(), FleetEntry {
+                id: car.id.to_owned(),
+                brand: car.brand.to_owned(),
+                status,
+                error,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psa::model::VehiclesListElement;
+    use crate::testutil::{self, api_config, car, http_server, start};
+
+    fn cars(count: usize) -> VehiclesList {
+        VehiclesList {
+            vehicles: (0..count).map(|i| VehiclesListElement {
+                id: format!("car-{}", i),
+                vin: format!("VIN{}", i),
+                ..car()
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn records_failing_vehicle_and_fetches_the_others() {
+        let status = serde_json::to_string(&testutil::status(start())).unwrap();
+        let (url, requests) = http_server(move |r| {
+            if r.path.contains("/car-2/") { (500, "{}".to_owned()) } else { (200, status.clone()) }
+        });
+        let api = api_config(&url);
+
+        // more vehicles than workers to span several chunks
+        let report = fetch_fleet_status(&api, &cars(MAX_WORKERS + 2)).unwrap();
+        assert_eq!(report.vehicles.len(), MAX_WORKERS + 2);
+        assert_eq!(report.failed(), 1);
+        let failed = &report.vehicles["VIN2"];
+        assert!(failed.status.is_none());
+        assert!(failed.error.is_some());
+        let fetched = &report.vehicles["VIN5"];
+        assert_eq!(fetched.id, "car-5");
+        assert_eq!(fetched.brand, "Peugeot");
+        assert_eq!(fetched.status.as_ref().map(|s| s.soc()), Some(Some(80)));
+        assert_eq!(requests.try_iter().count(), MAX_WORKERS + 2);
+    }
+}
@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::events::{VehicleEvent, VehicleEventKind};
+use crate::geo;
+use crate::psa::model::VehicleStatus;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GeofenceShape {
+    Circle { latitude: f64, longitude: f64, radius_m: f64 },
+    // corners as [latitude, longitude]
+    Polygon { points: Vec<(f64, f64)> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Geofence {
+    pub name: String,
+    #[serde(flatten)]
+    pub shape: GeofenceShape,
+    // VINs the geofence applies to, all vehicles if empty
+    #[serde(default)]
+    pub vehicles: Vec<String>,
+}
+
+impl Geofence {
+    pub fn applies_to(&self, vin: &str) -> bool {
+        self.vehicles.is_empty() || self.vehicles.iter().any(|v| v.eq(vin))
+    }
+
+    pub fn contains(&self, position: (f64, f64)) -> bool {
+        match &self.shape {
+            GeofenceShape::Circle { latitude, longitude, radius_m } =>
+                geo::distance_m(position, (*latitude, *longitude)) <= *radius_m,
+            GeofenceShape::Polygon { points } => {
+                // the border belongs to the geofence like on a circle
+                if points.iter().zip(points.iter().cycle().skip(1)).any(|(a, b)| on_segment(position, *a, *b)) {
+                    return true;
+                }
+                // ray casting, good enough for areas of a few kilometers
+                let (y, x) = position;
+                let mut inside = false;
+                let mut j = points.len().wrapping_sub(1);
+                for i in 0..points.len() {
+                    let (yi, xi) = points[i];
+                    let (yj, xj) = points[j];
+                    if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+                inside
+            },
+        }
+    }
+}
+
+fn on_segment(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> bool {
+    let cross = (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0);
+    cross.abs() < 1e-12
+        && p.0 >= a.0.min(b.0) && p.0 <= a.0.max(b.0)
+        && p.1 >= a.1.min(b.1) && p.1 <= a.1.max(b.1)
+}
+
+/// Tracks per vehicle whether it is inside each geofence.
+pub struct GeofenceTracker {
+    geofences: Vec<Geofence>,
+    inside: HashMap<(String, String), bool>,
+}
+
+impl GeofenceTracker {
+    pub fn new(geofences: Vec<Geofence>) -> GeofenceTracker {
+        GeofenceTracker {
+            geofences,
+            inside: HashMap::new(),
+        }
+    }
+
+    /// Updates the state with the position of the snapshot and returns the
+    /// enter and leave events. The first position of a vehicle only sets the
+    /// initial state.
+    pub fn update(&mut self, vin: &str, status: &VehicleStatus) -> Vec<VehicleEvent> {
+        let mut events = vec![];
+        let position = match status.position() {
+            Some(position) => position,
+            None => return events,
+        };
+
+        for fence in self.geofences.iter().filter(|f| f.applies_to(vin)) {
+            let inside = fence.contains(position);
+            let previous = self.inside.insert((vin.to_owned(), fence.name.to_owned()), inside);
+            let kind = match (previous, inside) {
+                (Some(false), true) => VehicleEventKind::GeofenceEntered { geofence: fence.name.to_owned() },
+                (Some(true), false) => VehicleEventKind::GeofenceLeft { geofence: fence.name.to_owned() },
+                _ => continue,
+            };
+            events.push(VehicleEvent {
+                vin: vin.to_owned(),
+                timestamp: status.last_position.properties.created_at,
+                kind,
+            });
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{start, status, VIN};
+
+    fn polygon(points: &[(f64, f64)]) -> Geofence {
+        Geofence { name: "area".to_owned(), shape: GeofenceShape::Polygon { points: points.to_vec() }, vehicles: vec![] }
+    }
+
+    fn circle(radius_m: f64) -> Geofence {
+        Geofence {
+            name: "home".to_owned(),
+            shape: GeofenceShape::Circle { latitude: 48.0, longitude: 11.0, radius_m },
+            vehicles: vec![],
+        }
+    }
+
+    fn at(latitude: f32, longitude: f32) -> VehicleStatus {
+        let mut status = status(start());
+        status.last_position.geometry.coordinates = vec![longitude, latitude];
+        status
+    }
+
+    #[test]
+    fn contains_points_of_concave_polygon() {
+        // U shape open to the north with the notch between longitude 1 and 2
+        let fence = polygon(&[(0.0, 0.0), (3.0, 0.0), (3.0, 1.0), (1.0, 1.0), (1.0, 2.0), (3.0, 2.0), (3.0, 3.0), (0.0, 3.0)]);
+        for (position, inside) in [
+            ((2.0, 0.5), true),
+            ((2.0, 2.5), true),
+            ((0.5, 1.5), true),
+            ((2.0, 1.5), false),
+            ((4.0, 0.5), false),
+            ((-1.0, 1.5), false),
+            ((0.5, 3.5), false),
+        ] {
+            assert_eq!(fence.contains(position), inside, "{:?}", position);
+        }
+    }
+
+    #[test]
+    fn contains_points_on_polygon_border() {
+        let fence = polygon(&[(0.0, 0.0), (3.0, 0.0), (3.0, 1.0), (1.0, 1.0), (1.0, 2.0), (3.0, 2.0), (3.0, 3.0), (0.0, 3.0)]);
+        for position in [(0.0, 1.5), (2.0, 0.0), (3.0, 0.5), (3.0, 2.5), (2.0, 3.0), (1.5, 1.0), (1.0, 1.5), (3.0, 3.0), (0.0, 0.0)] {
+            assert!(fence.contains(position), "{:?}", position);
+        }
+        assert!(!polygon(&[]).contains((0.0, 0.0)));
+    }
+
+    #[test]
+    fn contains_points_within_radius() {
+        let fence = circle(1000.0);
+        assert!(fence.contains((48.0, 11.0)));
+        // a thousandth of a degree of latitude is about 111 m
+        assert!(fence.contains((48.008, 11.0)));
+        assert!(!fence.contains((48.01, 11.0)));
+        assert!(fence.contains((48.0, 11.013)));
+        assert!(!fence.contains((48.0, 11.014)));
+    }
+
+    #[test]
+    fn reports_enter_and_leave_after_initial_position() {
+        let mut tracker = GeofenceTracker::new(vec![circle(1000.0)]);
+        let names = |events: Vec<VehicleEvent>| events.into_iter().map(|e| e.kind.name()).collect::<Vec<_>>();
+
+        assert!(tracker.update(VIN, &at(48.0, 11.0)).is_empty());
+        assert!(tracker.update(VIN, &at(48.001, 11.0)).is_empty());
+        assert_eq!(names(tracker.update(VIN, &at(48.1, 11.0))), vec!["geofence_left"]);
+        assert!(tracker.update(VIN, &at(48.2, 11.0)).is_empty());
+
+        let mut unknown = at(48.0, 11.0);
+        unknown.last_position.geometry.coordinates.clear();
+        assert!(tracker.update(VIN, &unknown).is_empty());
+
+        let entered = tracker.update(VIN, &at(48.0, 11.0));
+        assert_eq!(entered[0].kind, VehicleEventKind::GeofenceEntered { geofence: "home".to_owned() });
+        assert_eq!(entered[0].timestamp, start());
+
+        // starting outside, another vehicle has its own state
+        assert!(tracker.update("OTHERVIN", &at(48.1, 11.0)).is_empty());
+        assert_eq!(names(tracker.update("OTHERVIN", &at(48.0, 11.0))), vec!["geofence_entered"]);
+    }
+
+    #[test]
+    fn ignores_vehicles_the_geofence_does_not_apply_to() {
+        let mut tracker = GeofenceTracker::new(vec![Geofence { vehicles: vec!["OTHERVIN".to_owned()], ..circle(1000.0) }]);
+        tracker.update(VIN, &at(48.0, 11.0));
+        assert!(tracker.update(VIN, &at(48.1, 11.0)).is_empty());
+    }
+}
@@ -0,0 +1,249 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error, fmt};
+
+use crate::events::{VehicleEvent, VehicleEventKind};
+use crate::history::{HistoryDb, Snapshot};
+use crate::psa::model::VehicleStatus;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    // 12V battery voltage below this is reported
+    pub low_voltage: f32,
+    // voltage loss in volts over the window reported as declining
+    pub voltage_drop: f32,
+    pub voltage_window_days: i64,
+    // status older than this is reported as stale
+    pub stale_days: i64,
+    // SoC in percent reported while parked and unplugged
+    pub low_soc: u32,
+}
+
+// longer periods are surely a mistake, this also keeps the date arithmetic
+// within the range of chrono
+const MAX_DAYS: i64 = 3650;
+
+impl HealthConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, days) in [("voltage_window_days", self.voltage_window_days), ("stale_days", self.stale_days)] {
+            if !(1..=MAX_DAYS).contains(&days) {
+                return Err(format!("health {} must be between 1 and {}, got {}", name, MAX_DAYS, days));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            low_voltage: 12.0,
+            voltage_drop: 0.3,
+            voltage_window_days: 7,
+            stale_days: 3,
+            low_soc: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum HealthWarning {
+    LowVoltage { voltage: f32 },
+    DecliningVoltage { volts_per_day: f32 },
+    StaleData { days: i64 },
+    PrivacyMode { state: String },
+    LowSocParked { soc: u32 },
+}
+
+impl HealthWarning {
+    pub fn rule(&self) -> &'static str {
+        match self {
+            HealthWarning::LowVoltage { .. } => "low_voltage",
+            HealthWarning::DecliningVoltage { .. } => "declining_voltage",
+            HealthWarning::StaleData { .. } => "stale_data",
+            HealthWarning::PrivacyMode { .. } => "privacy_mode",
+            HealthWarning::LowSocParked { .. } => "low_soc_parked",
+        }
+    }
+}
+
+impl fmt::Display for HealthWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HealthWarning::LowVoltage { voltage } => write!(f, "12V battery low at {:.2} V", voltage),
+            HealthWarning::DecliningVoltage { volts_per_day } => write!(f, "12V battery declining by {:.2} V per day", -volts_per_day),
+            HealthWarning::StaleData { days } => write!(f, "no status update for {} days", days),
+            HealthWarning::PrivacyMode { state } => write!(f, "privacy mode {} blocks data", state),
+            HealthWarning::LowSocParked { soc } => write!(f, "parked unplugged with {}% SoC", soc),
+        }
+    }
+}
+
+/// Least squares slope of the 12V voltage in volts per day.
+fn voltage_trend(snapshots: &[&Snapshot]) -> Option<f32> {
+    let first = snapshots.first()?.updated_at;
+    let points = snapshots.iter()
+        .map(|s| ((s.updated_at - first).num_seconds() as f64 / 86400.0, s.battery_voltage as f64))
+        .collect::<Vec<_>>();
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let var_x = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f64>();
+    if var_x == 0.0 {
+        return None;
+    }
+    let cov = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum::<f64>();
+    Some((cov / var_x) as f32)
+}
+
+/// Applies the rules to the snapshots of a vehicle ordered by time, the last
+/// one is the current state.
+pub fn check(config: &HealthConfig, snapshots: &[Snapshot], now: DateTime<Utc>) -> Vec<HealthWarning> {
+    let mut warnings = vec![];
+    let latest = match snapshots.last() {
+        Some(latest) => latest,
+        None => return warnings,
+    };
+
+    if latest.battery_voltage < config.low_voltage {
+        warnings.push(HealthWarning::LowVoltage { voltage: latest.battery_voltage });
+    }
+
+    // only parked values, the voltage rises while driving or charging
+    let window_start = latest.updated_at - Duration::days(config.voltage_window_days);
+    let parked = snapshots.iter()
+        .filter(|s| s.updated_at >= window_start && !s.moving && !s.ignition_on() && !s.plugged)
+        .collect::<Vec<_>>();
+    if let Some(volts_per_day) = voltage_trend(&parked) {
+        if volts_per_day * (config.voltage_window_days as f32) <= -config.voltage_drop {
+            warnings.push(HealthWarning::DecliningVoltage { volts_per_day });
+        }
+    }
+
+    let age = now - latest.updated_at;
+    if age >= Duration::days(config.stale_days) {
+        warnings.push(HealthWarning::StaleData { days: age.num_days() });
+    }
+
+    if !latest.privacy.eq("None") {
+        warnings.push(HealthWarning::PrivacyMode { state: latest.privacy.to_owned() });
+    }
+
+    if let Some(soc) = latest.soc {
+        if soc < config.low_soc && !latest.moving && !latest.ignition_on() && !latest.plugged {
+            warnings.push(HealthWarning::LowSocParked { soc });
+        }
+    }
+
+    warnings
+}
+
+/// Applies the rules to the polled snapshots and reports warnings when they
+/// become active.
+pub struct HealthMonitor {
+    config: HealthConfig,
+    recent: HashMap<String, Vec<Snapshot>>,
+    active: HashMap<String, Vec<&'static str>>,
+}
+
+impl HealthMonitor {
+    pub fn new(config: HealthConfig) -> HealthMonitor {
+        HealthMonitor {
+            config,
+            recent: HashMap::new(),
+            active: HashMap::new(),
+        }
+    }
+
+    /// Snapshots recorded before the start within the voltage window, so a
+    /// trend is detected without waiting for new polls after a restart.
+    pub fn seed(&mut self, vin: &str, db: &HistoryDb) -> Result<(), Box<dyn Error>> {
+        let now = Utc::now();
+        let snapshots = db.snapshots(vin, now - Duration::days(self.config.voltage_window_days), now)?;
+        self.recent.insert(vin.to_owned(), snapshots);
+        Ok(())
+    }
+
+    pub fn update(&mut self, vin: &str, status: &VehicleStatus) -> Vec<VehicleEvent> {
+        let snapshot = match Snapshot::from_status(vin, status) {
+            Ok(snapshot) => snapshot,
+            Err(_) => return vec![],
+        };
+
+        let recent = self.recent.entry(vin.to_owned()).or_default();
+        // None orders before any time
+        if recent.last().map(|s| s.updated_at) < Some(snapshot.updated_at) {
+            recent.push(snapshot);
+        }
+        let window_start = Utc::now() - Duration::days(self.config.voltage_window_days);
+        recent.retain(|s| s.updated_at >= window_start || s.updated_at == status.updated_at);
+
+        let warnings = check(&self.config, recent, Utc::now());
+        let active = self.active.entry(vin.to_owned()).or_default();
+        let events = warnings.iter()
+            .filter(|w| !active.contains(&w.rule()))
+            .map(|w| VehicleEvent {
+                vin: vin.to_owned(),
+                timestamp: status.updated_at,
+                kind: VehicleEventKind::HealthWarning { warning: w.clone() },
+            })
+            .collect();
+        *active = warnings.iter().map(|w| w.rule()).collect();
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, snapshot, VIN};
+
+    fn parked(updated_at: DateTime<Utc>, voltage: f32) -> VehicleStatus {
+        let mut status = testutil::status(updated_at);
+        status.battery.voltage = voltage;
+        status.energy[0].extension.as_mut().unwrap().electric.charging.plugged = false;
+        status
+    }
+
+    fn declining(warnings: &[VehicleEvent]) -> bool {
+        warnings.iter().any(|e| matches!(&e.kind, VehicleEventKind::HealthWarning { warning: HealthWarning::DecliningVoltage { .. } }))
+    }
+
+    #[test]
+    fn detects_declining_voltage_from_seeded_history() {
+        let db = HistoryDb::open(":memory:").unwrap();
+        let now = Utc::now();
+        for (days, voltage) in [(6, 13.4), (4, 13.1), (2, 12.9)] {
+            db.insert(VIN, &parked(now - Duration::days(days), voltage)).unwrap();
+        }
+
+        let mut fresh = HealthMonitor::new(HealthConfig::default());
+        assert!(!declining(&fresh.update(VIN, &parked(now, 12.6))));
+
+        let mut seeded = HealthMonitor::new(HealthConfig::default());
+        seeded.seed(VIN, &db).unwrap();
+        assert!(declining(&seeded.update(VIN, &parked(now, 12.6))));
+    }
+
+    #[test]
+    fn reports_low_soc_only_when_parked_with_ignition_off() {
+        let config = HealthConfig::default();
+        let low_soc = |s: Snapshot| check(&config, &[s], testutil::start()).contains(&HealthWarning::LowSocParked { soc: 10 });
+
+        assert!(low_soc(snapshot(0, 100.0, 10)));
+        assert!(!low_soc(Snapshot { ignition: "StartUp".to_owned(), ..snapshot(0, 100.0, 10) }));
+        assert!(!low_soc(Snapshot { plugged: true, ..snapshot(0, 100.0, 10) }));
+        assert!(!low_soc(snapshot(0, 100.0, 30)));
+    }
+
+    #[test]
+    fn rejects_day_counts_out_of_range() {
+        assert!(HealthConfig::default().validate().is_ok());
+        let config = HealthConfig { voltage_window_days: i64::MAX / 2, ..Default::default() };
+        assert!(config.validate().unwrap_err().contains("voltage_window_days"));
+        assert!(HealthConfig { stale_days: 0, ..Default::default() }.validate().is_err());
+        assert!(HealthConfig { stale_days: MAX_DAYS, voltage_window_days: MAX_DAYS, ..Default::default() }.validate().is_ok());
+    }
+}
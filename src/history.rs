@@ -0,0 +1,239 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, Row};
+use serde::Serialize;
+use std::error::Error;
+
+use crate::psa::api::ApiClient;
+use crate::psa::model::{VehicleStatus, VehiclesListElement};
+use crate::watch::WatchHandler;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS snapshots (
+    id INTEGER PRIMARY KEY,
+    vin TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    soc INTEGER,
+    autonomy INTEGER,
+    mileage REAL NOT NULL,
+    battery_voltage REAL NOT NULL,
+    temperature REAL NOT NULL,
+    latitude REAL,
+    longitude REAL,
+    moving INTEGER NOT NULL,
+    ignition TEXT NOT NULL,
+    plugged INTEGER NOT NULL,
+    charging_status TEXT,
+    charging_rate INTEGER,
+    charging_mode TEXT,
+    battery_capacity INTEGER,
+    battery_residual INTEGER,
+    privacy TEXT NOT NULL,
+    raw TEXT NOT NULL,
+    UNIQUE (vin, updated_at)
+);
+CREATE INDEX IF NOT EXISTS snapshots_vin_time ON snapshots (vin, updated_at);
+";
+
+const COLUMNS: &str = "vin, updated_at, soc, autonomy, mileage, battery_voltage, temperature, latitude, longitude, \
+    moving, ignition, plugged, charging_status, charging_rate, charging_mode, battery_capacity, battery_residual, privacy, raw";
+
+/// Normalized row of a stored vehicle status.
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    pub vin: String,
+    pub updated_at: DateTime<Utc>,
+    pub soc: Option<u32>,
+    pub autonomy: Option<u32>,
+    pub mileage: f32,
+    pub battery_voltage: f32,
+    pub temperature: f32,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub moving: bool,
+    pub ignition: String,
+    pub plugged: bool,
+    pub charging_status: Option<String>,
+    pub charging_rate: Option<u32>,
+    pub charging_mode: Option<String>,
+    pub battery_capacity: Option<u32>,
+    pub battery_residual: Option<u32>,
+    pub privacy: String,
+    #[serde(skip)]
+    pub raw: String,
+}
+
+impl Snapshot {
+    pub fn from_status(vin: &str, status: &VehicleStatus) -> Result<Snapshot, Box<dyn Error>> {
+        let charging = status.charging();
+        let load = status.electric_energy()
+            .and_then(|e| e.extension.as_ref())
+            .map(|e| &e.electric.battery.load);
+        let position = status.position();
+        Ok(Snapshot {
+            vin: vin.to_owned(),
+            updated_at: status.updated_at,
+            soc: status.soc(),
+            autonomy: status.electric_energy().and_then(|e| e.autonomy),
+            mileage: status.odometer.mileage,
+            battery_voltage: status.battery.voltage,
+            temperature: status.environment.air.temp,
+            latitude: position.map(|p| p.0),
+            longitude: position.map(|p| p.1),
+            moving: status.kinetic.moving,
+            ignition: status.ignition._type.to_owned(),
+            plugged: status.is_plugged(),
+            charging_status: charging.map(|c| c.status.to_owned()),
+            charging_rate: charging.map(|c| c.charging_rate),
+            charging_mode: charging.map(|c| c.charging_mode.to_owned()),
+            battery_capacity: load.map(|l| l.capacity),
+            battery_residual: load.map(|l| l.residual),
+            privacy: status.privacy.state.to_owned(),
+            raw: serde_json::to_string(status)?,
+        })
+    }
+
+    pub fn position(&self) -> Option<(f64, f64)> {
+        Some((self.latitude?, self.longitude?))
+    }
+
+    pub fn ignition_on(&self) -> bool {
+        !self.ignition.eq("Stop")
+    }
+
+    /// Usable battery capacity in kWh, the API reports it in Wh on most
+    /// vehicles and in kWh on some.
+    pub fn battery_capacity_kwh(&self) -> Option<f64> {
+        self.battery_capacity.map(|c| if c > 1000 { c as f64 / 1000.0 } else { c as f64 })
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Snapshot> {
+        Ok(Snapshot {
+            vin: row.get(0)?,
+            updated_at: row.get(1)?,
+            soc: row.get(2)?,
+            autonomy: row.get(3)?,
+            mileage: row.get(4)?,
+            battery_voltage: row.get(5)?,
+            temperature: row.get(6)?,
+            latitude: row.get(7)?,
+            longitude: row.get(8)?,
+            moving: row.get(9)?,
+            ignition: row.get(10)?,
+            plugged: row.get(11)?,
+            charging_status: row.get(12)?,
+            charging_rate: row.get(13)?,
+            charging_mode: row.get(14)?,
+            battery_capacity: row.get(15)?,
+            battery_residual: row.get(16)?,
+            privacy: row.get(17)?,
+            raw: row.get(18)?,
+        })
+    }
+}
+
+pub struct HistoryDb {
+    conn: Connection,
+}
+
+impl HistoryDb {
+    pub fn open(path: &str) -> Result<HistoryDb, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(HistoryDb { conn })
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Stores the status, returns `false` if a snapshot with the same
+    /// `updated_at` is already known.
+    pub fn insert(&self, vin: &str, status: &VehicleStatus) -> Result<bool, Box<dyn Error>> {
+        let s = Snapshot::from_status(vin, status)?;
+        let inserted = self.conn.execute(
+            &format!("INSERT OR IGNORE INTO snapshots ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)", COLUMNS),
+            params![s.vin, s.updated_at, s.soc, s.autonomy, s.mileage, s.battery_voltage, s.temperature, s.latitude, s.longitude,
+                s.moving, s.ignition, s.plugged, s.charging_status, s.charging_rate, s.charging_mode, s.battery_capacity,
+                s.battery_residual, s.privacy, s.raw],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// Snapshots of the vehicle within `[from, to]` ordered by time.
+    pub fn snapshots(&self, vin: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM snapshots WHERE vin = ?1 AND updated_at >= ?2 AND updated_at <= ?3 ORDER BY updated_at", COLUMNS))?;
+        let rows = stmt.query_map(params![vin, from, to], Snapshot::from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    pub fn latest(&self, vin: &str) -> Result<Option<Snapshot>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM snapshots WHERE vin = ?1 ORDER BY updated_at DESC LIMIT 1", COLUMNS))?;
+        let mut rows = stmt.query_map(params![vin], Snapshot::from_row)?;
+        Ok(rows.next().transpose()?)
+    }
+
+    pub fn vins(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare("SELECT DISTINCT vin FROM snapshots ORDER BY vin")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+}
+
+impl WatchHandler for HistoryDb {
+    fn on_status(&mut self, _client: &mut ApiClient, car: &VehiclesListElement, status: &VehicleStatus) -> Result<(), Box<dyn Error>> {
+        self.insert(&car.vin, status)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{start, status, VIN};
+    use chrono::Duration;
+
+    fn db(minutes: &[i64]) -> HistoryDb {
+        let db = HistoryDb::open(":memory:").unwrap();
+        for m in minutes {
+            assert!(db.insert(VIN, &status(start() + Duration::minutes(*m))).unwrap());
+        }
+        db
+    }
+
+    #[test]
+    fn skips_known_snapshots() {
+        let db = db(&[0]);
+        assert!(!db.insert(VIN, &status(start())).unwrap());
+        assert!(db.insert("OTHERVIN", &status(start())).unwrap());
+        assert_eq!(db.vins().unwrap(), vec!["OTHERVIN", VIN]);
+
+        let stored = db.latest(VIN).unwrap().unwrap();
+        assert_eq!(stored.soc, Some(80));
+        // the API reports the coordinates as f32
+        assert_eq!(stored.position().map(|(lat, lon)| ((lat * 1000.0).round(), (lon * 1000.0).round())), Some((48137.0, 11575.0)));
+        assert!(stored.plugged);
+        assert_eq!(stored.charging_status.as_deref(), Some("Stopped"));
+        assert_eq!(stored.battery_capacity_kwh(), Some(46.0));
+        assert!(!stored.ignition_on());
+    }
+
+    #[test]
+    fn selects_snapshots_within_inclusive_range() {
+        let db = db(&[20, 0, 10, 30]);
+        db.insert("OTHERVIN", &status(start() + Duration::minutes(15))).unwrap();
+        let times = |from: i64, to: i64| db.snapshots(VIN, start() + Duration::minutes(from), start() + Duration::minutes(to)).unwrap()
+            .iter().map(|s| (s.updated_at - start()).num_minutes()).collect::<Vec<_>>();
+        assert_eq!(times(10, 20), vec![10, 20]);
+        assert_eq!(times(-10, 60), vec![0, 10, 20, 30]);
+        assert_eq!(times(11, 19), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn returns_latest_snapshot_by_time() {
+        let db = db(&[10, 30, 20]);
+        assert_eq!(db.latest(VIN).unwrap().unwrap().updated_at, start() + Duration::minutes(30));
+        assert!(db.latest("OTHERVIN").unwrap().is_none());
+    }
+}
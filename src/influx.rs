@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fs::OpenOptions, io::Write};
+
+use crate::history::Snapshot;
+use crate::psa::api::ApiClient;
+use crate::psa::model::{VehicleStatus, VehiclesListElement};
+use crate::watch::WatchHandler;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InfluxConfig {
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+    // write endpoint incl. query, e.g. http://localhost:8086/api/v2/write?org=home&bucket=cars
+    #[serde(default)]
+    pub url: Option<String>,
+    // sent as `Authorization: Token <token>`
+    #[serde(default)]
+    pub token: Option<String>,
+    // file the lines are appended to
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+fn default_measurement() -> String {
+    "vehicle_status".to_owned()
+}
+
+fn escape_tag(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+fn escape_string_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Converts the snapshot into a line protocol point with nanosecond
+/// timestamp. An empty brand is left out, tags can not be empty.
+pub fn to_line(measurement: &str, brand: &str, s: &Snapshot) -> String {
+    let mut fields = vec![
+        format!("mileage={}", s.mileage),
+        format!("battery_voltage={}", s.battery_voltage),
+        format!("temperature={}", s.temperature),
+        format!("plugged={}", s.plugged),
+        format!("moving={}", s.moving),
+    ];
+    if let Some(soc) = s.soc {
+        fields.push(format!("soc={}i", soc));
+    }
+    if let Some(autonomy) = s.autonomy {
+        fields.push(format!("autonomy={}i", autonomy));
+    }
+    if let Some(rate) = s.charging_rate {
+        fields.push(format!("charging_rate={}i", rate));
+    }
+    if let Some(status) = &s.charging_status {
+        fields.push(format!("charging_status={}", escape_string_field(status)));
+    }
+    if let Some((lat, lon)) = s.position() {
+        fields.push(format!("lat={}", lat));
+        fields.push(format!("lon={}", lon));
+    }
+
+    let mut tags = format!("{},vin={}", escape_tag(measurement), escape_tag(&s.vin));
+    if !brand.is_empty() {
+        tags.push_str(&format!(",brand={}", escape_tag(brand)));
+    }
+    format!("{} {} {}", tags, fields.join(","), s.updated_at.timestamp_nanos_opt().unwrap_or_default())
+}
+
+pub struct InfluxWriter {
+    config: InfluxConfig,
+    http: reqwest::blocking::Client,
+}
+
+impl InfluxWriter {
+    pub fn new(config: &InfluxConfig) -> Result<InfluxWriter, Box<dyn Error>> {
+        if config.url.is_none() && config.file.is_none() {
+            return Err("Influx export needs an url or a file".into());
+        }
+        Ok(InfluxWriter {
+            config: config.clone(),
+            http: reqwest::blocking::Client::new(),
+        })
+    }
+
+    pub fn measurement(&self) -> &str {
+        &self.config.measurement
+    }
+
+    /// Writes the lines to the configured endpoint and file.
+    pub fn write(&self, lines: &[String]) -> Result<(), Box<dyn Error>> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let body = lines.join("\n") + "\n";
+
+        if let Some(file) = &self.config.file {
+            let mut f = OpenOptions::new().append(true).create(true).open(file)?;
+            f.write_all(body.as_bytes())?;
+        }
+        if let Some(url) = &self.config.url {
+            let mut req = self.http.post(url)
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(body);
+            if let Some(token) = &self.config.token {
+                req = req.header("Authorization", format!("Token {}", token));
+            }
+            req.send()?.error_for_status()?;
+        }
+        Ok(())
+    }
+}
+
+impl WatchHandler for InfluxWriter {
+    fn on_status(&mut self, _client: &mut ApiClient, car: &VehiclesListElement, status: &VehicleStatus) -> Result<(), Box<dyn Error>> {
+        let snapshot = Snapshot::from_status(&car.vin, status)?;
+        self.write(&[to_line(&self.config.measurement, &car.brand, &snapshot)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, car, http_server, snapshot, start, VIN};
+
+    #[test]
+    fn escapes_tags_and_leaves_out_empty_brand() {
+        let line = to_line("cars, all", "", &snapshot(0, 100.0, 80));
+        assert!(line.starts_with(&format!("cars\\,\\ all,vin={} mileage=100,", VIN)), "{}", line);
+
+        let line = to_line("m", "Peu\\geot=1", &snapshot(0, 100.0, 80));
+        assert!(line.starts_with(&format!("m,vin={},brand=Peu\\\\geot\\=1 ", VIN)), "{}", line);
+    }
+
+    #[test]
+    fn posts_status_lines_to_endpoint() {
+        let (url, requests) = http_server(|_| (204, "".to_owned()));
+        let mut writer = InfluxWriter::new(&InfluxConfig {
+            measurement: default_measurement(),
+            url: Some(format!("{}/api/v2/write?org=home&bucket=cars", url)),
+            token: Some("secret".to_owned()),
+            file: None,
+        }).unwrap();
+        let api = testutil::api_config("http://127.0.0.1:1");
+        writer.on_status(&mut ApiClient::new(&api), &car(), &testutil::status(start())).unwrap();
+
+        let request = requests.recv().unwrap();
+        assert_eq!(request.path, "/api/v2/write?org=home&bucket=cars");
+        assert_eq!(request.headers["authorization"], "Token secret");
+        assert_eq!(request.body, format!("vehicle_status,vin={},brand=Peugeot mileage=12345.6,battery_voltage=12.6,temperature=12,\
+            plugged=true,moving=false,soc=80i,autonomy=240i,charging_rate=0i,charging_status=\"Stopped\",lat=48.137001037597656,lon=11.574999809265137 {}\n",
+            VIN, start().timestamp_nanos_opt().unwrap()));
+    }
+}
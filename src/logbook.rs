@@ -0,0 +1,467 @@
+use chrono::{DateTime, Local, Utc};
+use rusqlite::{params, Row};
+use serde::Serialize;
+use std::{error::Error, fmt, io::Write, str::FromStr};
+
+use crate::history::HistoryDb;
+use crate::trips::Trip;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS logbook (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    vin TEXT NOT NULL,
+    trip_id INTEGER NOT NULL,
+    start_time TEXT NOT NULL,
+    end_time TEXT NOT NULL,
+    start_address TEXT NOT NULL,
+    end_address TEXT NOT NULL,
+    start_odometer REAL NOT NULL,
+    end_odometer REAL NOT NULL,
+    purpose TEXT NOT NULL,
+    note TEXT NOT NULL,
+    corrects INTEGER,
+    recorded_at TEXT NOT NULL,
+    prev_hash TEXT NOT NULL,
+    hash TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS logbook_head (
+    vin TEXT PRIMARY KEY,
+    count INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    mac TEXT NOT NULL
+);
+";
+
+const COLUMNS: &str = "seq, vin, trip_id, start_time, end_time, start_address, end_address, start_odometer, end_odometer, \
+    purpose, note, corrects, recorded_at, prev_hash, hash";
+
+// previous hash of the first entry of a vehicle
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Random secret for [`Logbook::open`], kept in the config file and not in
+/// the database.
+pub fn generate_key() -> Result<String, Box<dyn Error>> {
+    let mut key = [0; 32];
+    openssl::rand::rand_bytes(&mut key)?;
+    Ok(hex(&key))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac(key: &str, content: &str) -> Result<String, Box<dyn Error>> {
+    let key = openssl::pkey::PKey::hmac(key.as_bytes())?;
+    let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key)?;
+    signer.update(content.as_bytes())?;
+    Ok(hex(&signer.sign_to_vec()?))
+}
+
+/// Why a logbook failed verification.
+#[derive(Debug, PartialEq)]
+pub enum Violation {
+    // sequence number of the first entry modified, inserted or reordered
+    Modified(i64),
+    // entries at the end were removed, the head counts more
+    Truncated { expected: i64, found: i64 },
+    // the head does not match the key, the chain was recomputed or the key changed
+    Head,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Modified(seq) => write!(f, "was modified at entry {}", seq),
+            Violation::Truncated { expected, found } => write!(f, "has {} of {} entries, the latest were removed", found, expected),
+            Violation::Head => write!(f, "head does not match the logbook key"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LogbookError {
+    pub message: String
+}
+
+impl Error for LogbookError {}
+
+impl fmt::Display for LogbookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Logbook Error: {}", self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Purpose {
+    Business,
+    Commute,
+    Private,
+}
+
+impl fmt::Display for Purpose {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Purpose::Business => write!(f, "business"),
+            Purpose::Commute => write!(f, "commute"),
+            Purpose::Private => write!(f, "private"),
+        }
+    }
+}
+
+impl FromStr for Purpose {
+    type Err = LogbookError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "business" => Ok(Purpose::Business),
+            "commute" => Ok(Purpose::Commute),
+            "private" => Ok(Purpose::Private),
+            _ => Err(LogbookError { message: format!("Unknown purpose {}, use business, commute or private", s) }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    // semicolon separated with BOM, opens directly in LibreOffice and Excel
+    OdsCsv,
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = LogbookError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "ods-csv" => Ok(ExportFormat::OdsCsv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(LogbookError { message: format!("Unknown format {}, use csv, ods-csv or json", s) }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogbookEntry {
+    pub seq: i64,
+    pub vin: String,
+    pub trip_id: i64,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub start_address: String,
+    pub end_address: String,
+    pub start_odometer: f32,
+    pub end_odometer: f32,
+    pub purpose: Purpose,
+    pub note: String,
+    // sequence number of the entry replaced by this one
+    pub corrects: Option<i64>,
+    pub recorded_at: DateTime<Utc>,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl LogbookEntry {
+    fn compute_hash(&self, key: &str) -> Result<String, Box<dyn Error>> {
+        let content = format!("{}|{}|{}|{}|{}|{}|{}|{:.1}|{:.1}|{}|{}|{}|{}|{}",
+            self.seq, self.vin, self.trip_id, self.start_time.to_rfc3339(), self.end_time.to_rfc3339(),
+            self.start_address, self.end_address, self.start_odometer, self.end_odometer, self.purpose,
+            self.note, self.corrects.map_or("".to_owned(), |c| c.to_string()), self.recorded_at.to_rfc3339(), self.prev_hash);
+        hmac(key, &content)
+    }
+
+    pub fn distance_km(&self) -> f32 {
+        self.end_odometer - self.start_odometer
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<LogbookEntry> {
+        let purpose: String = row.get(9)?;
+        Ok(LogbookEntry {
+            seq: row.get(0)?,
+            vin: row.get(1)?,
+            trip_id: row.get(2)?,
+            start_time: row.get(3)?,
+            end_time: row.get(4)?,
+            start_address: row.get(5)?,
+            end_address: row.get(6)?,
+            start_odometer: row.get(7)?,
+            end_odometer: row.get(8)?,
+            purpose: purpose.parse().map_err(|e| rusqlite::Error::FromSqlConversionFailure(9, rusqlite::types::Type::Text, Box::new(e)))?,
+            note: row.get(10)?,
+            corrects: row.get(11)?,
+            recorded_at: row.get(12)?,
+            prev_hash: row.get(13)?,
+            hash: row.get(14)?,
+        })
+    }
+}
+
+fn format_position(position: Option<(f64, f64)>) -> String {
+    position.map_or("".to_owned(), |p| format!("{:.5}, {:.5}", p.0, p.1))
+}
+
+struct Head {
+    count: i64,
+    hash: String,
+    mac: String,
+}
+
+impl Head {
+    fn compute_mac(&self, vin: &str, key: &str) -> Result<String, Box<dyn Error>> {
+        hmac(key, &format!("{}|{}|{}", vin, self.count, self.hash))
+    }
+}
+
+/// Entries are chained by a HMAC over their content and the previous hash,
+/// the number of entries and the latest hash are kept as separately signed
+/// head. Without the key neither can be recomputed after a change.
+pub struct Logbook<'a> {
+    db: &'a HistoryDb,
+    key: &'a str,
+}
+
+impl<'a> Logbook<'a> {
+    pub fn open(db: &'a HistoryDb, key: &'a str) -> Result<Logbook<'a>, Box<dyn Error>> {
+        db.connection().execute_batch(SCHEMA)?;
+        Ok(Logbook { db, key })
+    }
+
+    fn head(&self, vin: &str) -> Result<Option<Head>, Box<dyn Error>> {
+        let mut stmt = self.db.connection().prepare("SELECT count, hash, mac FROM logbook_head WHERE vin = ?1")?;
+        let mut rows = stmt.query_map(params![vin], |row| Ok(Head { count: row.get(0)?, hash: row.get(1)?, mac: row.get(2)? }))?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /// Entries of the vehicle in the order they were recorded.
+    pub fn entries(&self, vin: &str) -> Result<Vec<LogbookEntry>, Box<dyn Error>> {
+        let mut stmt = self.db.connection().prepare(&format!("SELECT {} FROM logbook WHERE vin = ?1 ORDER BY seq", COLUMNS))?;
+        let rows = stmt.query_map(params![vin], LogbookEntry::from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Appends the trip with its purpose, addresses default to the trip
+    /// coordinates. A trip already recorded can only be added again as
+    /// correction of its latest entry.
+    pub fn add(&self, trip: &Trip, purpose: Purpose, start_address: Option<String>, end_address: Option<String>,
+            note: Option<String>, corrects: Option<i64>) -> Result<LogbookEntry, Box<dyn Error>> {
+        let trip_id = trip.id.ok_or(LogbookError { message: "Trip is not stored".to_owned() })?;
+        let entries = self.entries(&trip.vin)?;
+
+        let latest_for_trip = entries.iter().rev().find(|e| e.trip_id == trip_id).map(|e| e.seq);
+        if latest_for_trip != corrects {
+            return Err(Box::new(LogbookError { message: match latest_for_trip {
+                Some(seq) => format!("Trip {} is already recorded as entry {}, add a correction", trip_id, seq),
+                None => format!("Entry to correct does not belong to trip {}", trip_id),
+            }}));
+        }
+
+        let mut entry = LogbookEntry {
+            // assigned on insert
+            seq: 0,
+            vin: trip.vin.to_owned(),
+            trip_id,
+            start_time: trip.start_time,
+            end_time: trip.end_time,
+            start_address: start_address.unwrap_or_else(|| format_position(trip.start_position)),
+            end_address: end_address.unwrap_or_else(|| format_position(trip.end_position)),
+            start_odometer: trip.start_mileage,
+            end_odometer: trip.end_mileage,
+            purpose,
+            note: note.unwrap_or_default(),
+            corrects,
+            recorded_at: Utc::now(),
+            prev_hash: entries.last().map_or(GENESIS_HASH.to_owned(), |e| e.hash.to_owned()),
+            hash: "".to_owned(),
+        };
+
+        let tx = self.db.connection().unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO logbook (vin, trip_id, start_time, end_time, start_address, end_address, start_odometer, end_odometer, \
+                purpose, note, corrects, recorded_at, prev_hash, hash) \
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, '')",
+            params![entry.vin, entry.trip_id, entry.start_time, entry.end_time, entry.start_address, entry.end_address,
+                entry.start_odometer, entry.end_odometer, entry.purpose.to_string(), entry.note, entry.corrects,
+                entry.recorded_at, entry.prev_hash],
+        )?;
+        entry.seq = tx.last_insert_rowid();
+        entry.hash = entry.compute_hash(self.key)?;
+        tx.execute("UPDATE logbook SET hash = ?1 WHERE seq = ?2", params![entry.hash, entry.seq])?;
+
+        let mut head = Head { count: entries.len() as i64 + 1, hash: entry.hash.to_owned(), mac: "".to_owned() };
+        head.mac = head.compute_mac(&entry.vin, self.key)?;
+        tx.execute("INSERT OR REPLACE INTO logbook_head (vin, count, hash, mac) VALUES (?1, ?2, ?3, ?4)",
+            params![entry.vin, head.count, head.hash, head.mac])?;
+        tx.commit()?;
+        Ok(entry)
+    }
+
+    /// Checks the hash chain of the vehicle against its head, detects
+    /// modified, inserted, reordered and removed entries.
+    pub fn verify(&self, vin: &str) -> Result<Option<Violation>, Box<dyn Error>> {
+        let entries = self.entries(vin)?;
+        let mut prev_hash = GENESIS_HASH.to_owned();
+        for entry in &entries {
+            if entry.prev_hash != prev_hash || entry.compute_hash(self.key)? != entry.hash {
+                return Ok(Some(Violation::Modified(entry.seq)));
+            }
+            prev_hash = entry.hash.to_owned();
+        }
+
+        let head = match self.head(vin)? {
+            Some(head) => head,
+            None if entries.is_empty() => return Ok(None),
+            None => return Ok(Some(Violation::Head)),
+        };
+        if head.compute_mac(vin, self.key)? != head.mac {
+            return Ok(Some(Violation::Head));
+        }
+        let found = entries.len() as i64;
+        if found < head.count {
+            return Ok(Some(Violation::Truncated { expected: head.count, found }));
+        }
+        if found > head.count || head.hash != prev_hash {
+            return Ok(Some(Violation::Head));
+        }
+        Ok(None)
+    }
+}
+
+pub fn export<W: Write>(mut w: W, entries: &[LogbookEntry], format: ExportFormat) -> Result<(), Box<dyn Error>> {
+    if format == ExportFormat::Json {
+        serde_json::to_writer_pretty(w, entries)?;
+        return Ok(());
+    }
+
+    let delimiter = if format == ExportFormat::OdsCsv {
+        w.write_all("\u{feff}".as_bytes())?;
+        b';'
+    } else {
+        b','
+    };
+    let time_format = "%Y-%m-%d %H:%M";
+
+    let mut csv = csv::WriterBuilder::new().delimiter(delimiter).from_writer(w);
+    csv.write_record(["seq", "date", "start", "end", "start_address", "end_address", "odometer_start", "odometer_end",
+        "distance_km", "purpose", "note", "corrects", "hash"])?;
+    for e in entries {
+        csv.write_record([
+            e.seq.to_string(),
+            e.start_time.with_timezone(&Local).format("%Y-%m-%d").to_string(),
+            e.start_time.with_timezone(&Local).format(time_format).to_string(),
+            e.end_time.with_timezone(&Local).format(time_format).to_string(),
+            e.start_address.to_owned(),
+            e.end_address.to_owned(),
+            format!("{:.1}", e.start_odometer),
+            format!("{:.1}", e.end_odometer),
+            format!("{:.1}", e.distance_km()),
+            e.purpose.to_string(),
+            e.note.to_owned(),
+            e.corrects.map_or("".to_owned(), |c| c.to_string()),
+            e.hash.to_owned(),
+        ])?;
+    }
+    csv.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{start, VIN};
+    use chrono::Duration;
+
+    const KEY: &str = "secret";
+
+    fn trip(id: i64) -> Trip {
+        Trip {
+            id: Some(id),
+            vin: VIN.to_owned(),
+            start_time: start() + Duration::hours(id),
+            end_time: start() + Duration::hours(id) + Duration::minutes(30),
+            start_position: Some((48.137, 11.575)),
+            end_position: Some((48.2, 11.6)),
+            start_mileage: 100.0 * id as f32,
+            end_mileage: 100.0 * id as f32 + 25.0,
+            start_soc: Some(80),
+            end_soc: Some(70),
+            energy_kwh: Some(4.6),
+        }
+    }
+
+    fn record(book: &Logbook, count: i64) {
+        for id in 1..=count {
+            book.add(&trip(id), Purpose::Business, None, None, None, None).unwrap();
+        }
+    }
+
+    #[test]
+    fn verifies_intact_logbook_and_corrections() {
+        let db = HistoryDb::open(":memory:").unwrap();
+        let book = Logbook::open(&db, KEY).unwrap();
+        assert_eq!(book.verify(VIN).unwrap(), None);
+        record(&book, 3);
+        assert!(book.add(&trip(2), Purpose::Private, None, None, None, None).is_err());
+        let correction = book.add(&trip(2), Purpose::Private, None, None, Some("typo".to_owned()), Some(2)).unwrap();
+        assert_eq!(correction.corrects, Some(2));
+        assert_eq!(book.entries(VIN).unwrap().len(), 4);
+        assert_eq!(book.verify(VIN).unwrap(), None);
+    }
+
+    #[test]
+    fn detects_modified_entry() {
+        let db = HistoryDb::open(":memory:").unwrap();
+        let book = Logbook::open(&db, KEY).unwrap();
+        record(&book, 3);
+        db.connection().execute("UPDATE logbook SET purpose = 'private' WHERE seq = 2", []).unwrap();
+        assert_eq!(book.verify(VIN).unwrap(), Some(Violation::Modified(2)));
+    }
+
+    #[test]
+    fn detects_removed_entry_in_the_middle() {
+        let db = HistoryDb::open(":memory:").unwrap();
+        let book = Logbook::open(&db, KEY).unwrap();
+        record(&book, 3);
+        db.connection().execute("DELETE FROM logbook WHERE seq = 2", []).unwrap();
+        assert_eq!(book.verify(VIN).unwrap(), Some(Violation::Modified(3)));
+    }
+
+    #[test]
+    fn detects_removed_latest_entries() {
+        let db = HistoryDb::open(":memory:").unwrap();
+        let book = Logbook::open(&db, KEY).unwrap();
+        record(&book, 3);
+        db.connection().execute("DELETE FROM logbook WHERE seq > 1", []).unwrap();
+        assert_eq!(book.verify(VIN).unwrap(), Some(Violation::Truncated { expected: 3, found: 1 }));
+
+        db.connection().execute("DELETE FROM logbook", []).unwrap();
+        assert_eq!(book.verify(VIN).unwrap(), Some(Violation::Truncated { expected: 3, found: 0 }));
+    }
+
+    #[test]
+    fn detects_chain_recomputed_without_key() {
+        let db = HistoryDb::open(":memory:").unwrap();
+        record(&Logbook::open(&db, KEY).unwrap(), 3);
+        db.connection().execute("UPDATE logbook SET purpose = 'private' WHERE seq = 2", []).unwrap();
+        db.connection().execute("DELETE FROM logbook WHERE seq = 3", []).unwrap();
+
+        // recompute the chain and head with a different key
+        let forged = Logbook::open(&db, "guess").unwrap();
+        let mut prev_hash = GENESIS_HASH.to_owned();
+        for mut entry in forged.entries(VIN).unwrap() {
+            entry.prev_hash = prev_hash;
+            entry.hash = entry.compute_hash("guess").unwrap();
+            db.connection().execute("UPDATE logbook SET prev_hash = ?1, hash = ?2 WHERE seq = ?3",
+                params![entry.prev_hash, entry.hash, entry.seq]).unwrap();
+            prev_hash = entry.hash;
+        }
+        let mut head = Head { count: 2, hash: prev_hash, mac: "".to_owned() };
+        head.mac = head.compute_mac(VIN, "guess").unwrap();
+        db.connection().execute("UPDATE logbook_head SET count = ?1, hash = ?2, mac = ?3",
+            params![head.count, head.hash, head.mac]).unwrap();
+
+        assert_eq!(forged.verify(VIN).unwrap(), None);
+        assert_eq!(Logbook::open(&db, KEY).unwrap().verify(VIN).unwrap(), Some(Violation::Modified(1)));
+    }
+}
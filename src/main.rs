@@ -2,16 +2,234 @@ mod config;
 mod parser;
 mod apk_parser;
 mod psa;
+mod fleet;
+mod cars_cache;
+mod geo;
+mod events;
+mod watch;
+mod geofence;
+mod health;
+mod notify;
+mod server;
+mod influx;
+mod abrp;
+mod charge_limit;
+mod smart_charge;
+mod preconditioning;
+mod mqtt;
+mod metrics;
+mod history;
+mod trips;
+mod charging;
+mod tariff;
+mod logbook;
+mod export;
+#[cfg(test)]
+mod testutil;
 
-use std::fs::{File, OpenOptions};
+use std::collections::BTreeMap;
+use chrono::{DateTime, Duration, Utc};
+use clap::{Args, Parser, Subcommand};
 
 use psa::api::{request_access_token, request_customer_id, ApiClient};
 use config::YamlConfigFile;
 use apk_parser::APK;
 use parser::FromFile;
+use cars_cache::CarsCache;
 
-const CARS_FILE: &str = "cars.yaml";
 const CONFIG_FILE: &str = "config.yaml";
+// status reads until a changed charging schedule is reported by the vehicle
+const SCHEDULE_POLLS: u32 = 6;
+const SCHEDULE_POLL_SECONDS: u64 = 5;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Profile of the config file to use, created if it does not exist
+    #[arg(short, long, global = true)]
+    profile: Option<String>,
+    /// Run the command for all configured profiles
+    #[arg(long, global = true, conflicts_with = "profile")]
+    all_profiles: bool,
+    /// Culture to read from the APK on first launch, e.g. `de-DE`, instead of asking for it
+    #[arg(long, global = true)]
+    culture: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the status of a single vehicle, asks for the VIN if not given
+    Status { vin: Option<String> },
+    /// Print the status of all vehicles of the account keyed by VIN
+    Fleet,
+    /// List the vehicles of the account
+    Cars {
+        /// Fetch the vehicle list even if the cache is still valid
+        #[arg(long)]
+        refresh: bool,
+    },
+    /// Poll the vehicles continuously and print status changes
+    Watch {
+        /// Publish to the MQTT broker configured in the mqtt section
+        #[arg(long)]
+        mqtt: bool,
+        /// Write line protocol points as configured in the influx section
+        #[arg(long)]
+        influx: bool,
+        /// Push live telemetry to the route planner configured in the abrp section
+        #[arg(long)]
+        abrp: bool,
+        /// Only log the commands of charge limit, smart charging and preconditioning planner
+        #[arg(long)]
+        dry_run: bool,
+        /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9898
+        #[arg(long, value_name = "ADDR")]
+        metrics: Option<String>,
+    },
+    /// Print the recorded status history of a vehicle as CSV
+    History {
+        vin: String,
+        #[command(flatten)]
+        range: TimeRange,
+    },
+    /// Detect and store the trips in the recorded history of a vehicle
+    Trips {
+        vin: String,
+        #[command(flatten)]
+        range: TimeRange,
+    },
+    /// Detect and store the charging sessions in the recorded history of a vehicle
+    Charging {
+        vin: String,
+        #[command(flatten)]
+        range: TimeRange,
+    },
+    /// Print the cost of the stored charging sessions of a vehicle as CSV
+    Costs {
+        vin: String,
+        #[command(flatten)]
+        range: TimeRange,
+        /// Sum up the costs per month and location
+        #[arg(long)]
+        monthly: bool,
+        /// Write the CSV to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Record trips in the tamper evident logbook and export it
+    Logbook {
+        #[command(subcommand)]
+        command: LogbookCommand,
+    },
+    /// Show or write the preconditioning programs of a vehicle
+    Preconditioning {
+        #[command(subcommand)]
+        command: PreconditioningCommand,
+    },
+    /// Export the current position, the position history or the trips of a vehicle
+    Export {
+        /// position, history or trips
+        kind: export::ExportKind,
+        vin: String,
+        /// geojson, gpx or kml
+        #[arg(long, default_value = "geojson")]
+        format: export::GeoFormat,
+        #[command(flatten)]
+        range: TimeRange,
+        /// Write to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Check the recorded history for 12V battery, stale data, privacy and SoC problems
+    Health {
+        /// Vehicle to check, all recorded vehicles if not given
+        vin: Option<String>,
+    },
+    /// Print the charging mode and delayed charging start of a vehicle, or set the start
+    ChargeSchedule {
+        vin: String,
+        /// `now` to charge immediately or the local start time, e.g. 22:30
+        #[arg(long)]
+        set: Option<psa::model::ChargingSchedule>,
+    },
+    /// Print the cheapest charging plan until departure as configured in the smart_charging section
+    SmartCharge { vin: String },
+    /// Write the recorded history of a vehicle as configured in the influx section
+    InfluxExport {
+        vin: String,
+        /// Brand tag of the points, defaults to the brand in the vehicle list of the profile
+        #[arg(long)]
+        brand: Option<String>,
+        #[command(flatten)]
+        range: TimeRange,
+    },
+    /// Send a test event to the configured notification sinks
+    NotifyTest,
+    /// Run a local HTTP API for the vehicles as configured in the serve section
+    Serve,
+    /// List the configured profiles
+    Profiles,
+}
+
+#[derive(Subcommand)]
+enum PreconditioningCommand {
+    /// Print the programs stored in the vehicle
+    Show { vin: String },
+    /// Write the schedules of the preconditioning section to the vehicle programs
+    Push { vin: String },
+}
+
+#[derive(Subcommand)]
+enum LogbookCommand {
+    /// List the stored trips of a vehicle which are not in the logbook yet
+    Pending { vin: String },
+    /// Add a stored trip to the logbook
+    Add {
+        trip_id: i64,
+        /// business, commute or private
+        #[arg(long)]
+        purpose: logbook::Purpose,
+        #[arg(long)]
+        start_address: Option<String>,
+        #[arg(long)]
+        end_address: Option<String>,
+        #[arg(long)]
+        note: Option<String>,
+        /// Sequence number of the entry this one corrects
+        #[arg(long)]
+        corrects: Option<i64>,
+    },
+    /// Export the logbook of a vehicle
+    Export {
+        vin: String,
+        /// csv, ods-csv or json
+        #[arg(long, default_value = "csv")]
+        format: logbook::ExportFormat,
+        /// Write to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Check that the logbook of a vehicle was not modified
+    Verify { vin: String },
+}
+
+#[derive(Args)]
+struct TimeRange {
+    /// Start of the time range, e.g. 2024-01-01T00:00:00Z
+    #[arg(long)]
+    from: Option<DateTime<Utc>>,
+    /// End of the time range, defaults to now
+    #[arg(long)]
+    to: Option<DateTime<Utc>>,
+}
+
+impl TimeRange {
+    fn bounds(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        (self.from.unwrap_or(DateTime::<Utc>::MIN_UTC), self.to.unwrap_or_else(Utc::now))
+    }
+}
 
 fn update_config_from_apk(cfg: &mut config::AppConfig, apk: &APK) {
     let mut api_config = cfg.api.borrow_mut();
@@ -28,15 +246,20 @@ fn update_config_from_apk(cfg: &mut config::AppConfig, apk: &APK) {
     cfg.brand_code = apk.brand_code.clone();
 }
 
-fn check_config () -> Result<config::AppConfig, Box<dyn std::error::Error>> {
-    let mut cfg = config::AppConfig::from_file(CONFIG_FILE.to_string())?;
+fn check_config(profile: &str, cfg: &mut config::AppConfig, culture: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
     if cfg.api.borrow().client_id.is_empty() {
-        println!("Please provide Car APK path: ");
+        println!("Please provide Car APK path for profile {}: ", profile);
         let mut car_apk_path = String::new();
         std::io::stdin().read_line(&mut car_apk_path)?;
         car_apk_path = car_apk_path.trim().to_string();
-        let apk = APK::from_file(car_apk_path)?;
-        update_config_from_apk(&mut cfg, &apk);
+        let apk = match culture {
+            Some(culture) => APK::from_file_with_culture(&car_apk_path, culture)?,
+            None => APK::from_file(car_apk_path)?,
+        };
+        for (file, split) in &apk.sources {
+            println!("{} read from {}", file, split);
+        }
+        update_config_from_apk(cfg, &apk);
         cfg.customer_id = "".to_string();
     }
 
@@ -57,48 +280,456 @@ fn check_config () -> Result<config::AppConfig, Box<dyn std::error::Error>> {
         cfg.customer_id = request_customer_id(&cfg.brand_code, &cfg.culture, &cfg.site_code, &access_token, &cfg.cert, &cfg.key)?;
     }
 
-    cfg.to_file(CONFIG_FILE.to_string())?;
-    Ok(cfg)
+    Ok(())
 }
 
-fn load_cars() -> Option<psa::model::VehiclesList> {
-    if std::path::Path::new(&CARS_FILE.to_string()).exists() {
-        if let Ok(f) = File::open(CARS_FILE.to_string()) {
-            if let Ok(cars) = serde_yaml::from_reader::<File, psa::model::VehiclesList>(f) {
-                return Some(cars);
+/// Returns the cached vehicle list, the list is fetched again if the cache
+/// is missing, corrupt, expired or does not know the requested VIN.
+fn get_cars(profile: &str, cfg: &config::AppConfig, vin: Option<&str>, refresh: bool) -> Result<psa::model::VehiclesList, Box<dyn std::error::Error>> {
+    let cars_file = cfg.cars_file(profile);
+    let cache = match CarsCache::load(&cars_file) {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("{}, fetching vehicle list", e);
+            None
+        }
+    };
+
+    let stale = match &cache {
+        Some(cache) => refresh
+            || cache.is_expired(cfg.cars_ttl())
+            || vin.is_some_and(|v| !cache.contains(v)),
+        None => true,
+    };
+    if !stale {
+        return Ok(cache.unwrap().cars);
+    }
+
+    let res = ApiClient::new(&cfg.api).connectedcar_list_vehicles()?;
+    if let Some(cache) = &cache {
+        let diff = cache.diff(&res.embedded);
+        for vin in &diff.added {
+            eprintln!("{}: vehicle {} added", profile, vin);
+        }
+        for vin in &diff.removed {
+            eprintln!("{}: vehicle {} removed", profile, vin);
+        }
+    }
+
+    let cache = CarsCache::new(res.embedded);
+    cache.save(&cars_file)?;
+    Ok(cache.cars)
+}
+
+/// Looks up the vehicle in the cached lists of all profiles first, so only a
+/// VIN unknown to every profile refreshes the lists.
+fn find_car(cfg_file: &config::ConfigFile, profiles: &[String], vin: &str) -> Result<Option<(String, psa::model::VehiclesListElement)>, Box<dyn std::error::Error>> {
+    for lookup in [None, Some(vin)] {
+        for name in profiles {
+            let cars = get_cars(name, &cfg_file.profiles[name], lookup, false)?;
+            if let Some(car) = cars.vehicles.into_iter().find(|c| c.vin.eq(vin)) {
+                return Ok(Some((name.to_owned(), car)));
             }
         }
     }
-    None
+    Ok(None)
 }
 
-fn save_cars(cars: &psa::model::VehiclesList) {
-    if let Ok(f) = OpenOptions::new().write(true).create(true).open(&CARS_FILE.to_string()) {
-        serde_yaml::to_writer(f, cars).unwrap();
+fn influx_export(cfg_file: &config::ConfigFile, vin: &str, brand: &str, range: &TimeRange) -> Result<(), Box<dyn std::error::Error>> {
+    let config = cfg_file.influx.as_ref().ok_or("No influx section configured")?;
+    let writer = influx::InfluxWriter::new(config)?;
+    let db = history::HistoryDb::open(&cfg_file.history_db)?;
+    let (from, to) = range.bounds();
+    let snapshots = db.snapshots(vin, from, to)?;
+    // keep requests at a reasonable size
+    for chunk in snapshots.chunks(1000) {
+        let lines = chunk.iter().map(|s| influx::to_line(writer.measurement(), brand, s)).collect::<Vec<_>>();
+        writer.write(&lines)?;
+    }
+    eprintln!("{} points written", snapshots.len());
+    Ok(())
+}
+
+fn output_writer(output: &Option<String>) -> Result<Box<dyn std::io::Write>, Box<dyn std::error::Error>> {
+    match output {
+        Some(path) => Ok(Box::new(std::fs::File::create(path)?)),
+        None => Ok(Box::new(std::io::stdout())),
+    }
+}
+
+/// Runs the commands working on local data only, returns `false` for
+/// commands which need the API.
+fn run_local(cfg_file: &config::ConfigFile, command: &Command) -> Result<bool, Box<dyn std::error::Error>> {
+    match command {
+        Command::History { vin, range } => {
+            let db = history::HistoryDb::open(&cfg_file.history_db)?;
+            let (from, to) = range.bounds();
+            println!("updated_at,soc,autonomy,mileage,temperature,battery_voltage,charging_status");
+            for s in db.snapshots(vin, from, to)? {
+                println!("{},{},{},{},{},{},{}", s.updated_at.to_rfc3339(), s.soc.map_or("".to_owned(), |v| v.to_string()),
+                    s.autonomy.map_or("".to_owned(), |v| v.to_string()), s.mileage, s.temperature, s.battery_voltage,
+                    s.charging_status.unwrap_or_default());
+            }
+        },
+        Command::Trips { vin, range } => {
+            let db = history::HistoryDb::open(&cfg_file.history_db)?;
+            let (from, to) = range.bounds();
+            let detected = trips::detect_trips(&db.snapshots(vin, from, to)?);
+            let inserted = trips::save_trips(&db, &detected)?;
+            eprintln!("{} trips detected, {} new", detected.len(), inserted);
+            for trip in trips::load_trips(&db, vin, from, to)? {
+                println!("{} - {} {:.1} km {} min {}", trip.start_time.to_rfc3339(), trip.end_time.to_rfc3339(), trip.distance_km(),
+                    trip.duration().num_minutes(), trip.energy_kwh.map_or("".to_owned(), |e| format!("{:.1} kWh", e)));
+            }
+        },
+        Command::Charging { vin, range } => {
+            let db = history::HistoryDb::open(&cfg_file.history_db)?;
+            let (from, to) = range.bounds();
+            let detected = charging::detect_sessions(&db.snapshots(vin, from, to)?);
+            let inserted = charging::save_sessions(&db, &detected)?;
+            eprintln!("{} charging sessions detected, {} new", detected.len(), inserted);
+            for session in charging::load_sessions(&db, vin, from, to)? {
+                println!("{} - {} {}% -> {}% {} {} {} {}{}", session.start_time.to_rfc3339(), session.end_time.to_rfc3339(),
+                    session.start_soc, session.end_soc,
+                    session.energy_kwh().map_or("".to_owned(), |e| format!("{:.1} kWh", e)),
+                    session.average_power_kw().map_or("".to_owned(), |p| format!("{:.1} kW", p)),
+                    session.mode.unwrap_or_default(),
+                    session.position.map_or("".to_owned(), |p| format!("{:.5},{:.5}", p.0, p.1)),
+                    if session.estimated { " estimated" } else { "" });
+            }
+        },
+        Command::Costs { vin, range, monthly, output } => {
+            let tariffs = cfg_file.tariffs.as_ref().ok_or("No tariffs section configured")?;
+            let db = history::HistoryDb::open(&cfg_file.history_db)?;
+            let (from, to) = range.bounds();
+            let costs = charging::load_sessions(&db, vin, from, to)?.iter()
+                .filter_map(|s| tariffs.session_cost(s))
+                .collect::<Vec<_>>();
+
+            let out = output_writer(output)?;
+            if *monthly {
+                tariff::write_monthly_csv(out, &tariff::monthly(&costs), &tariffs.currency)?;
+            } else {
+                tariff::write_sessions_csv(out, &costs, &tariffs.currency)?;
+            }
+        },
+        Command::Logbook { command } => {
+            let db = history::HistoryDb::open(&cfg_file.history_db)?;
+            let key = cfg_file.logbook_key.as_deref().ok_or("Logbook key missing")?;
+            let book = logbook::Logbook::open(&db, key)?;
+            match command {
+                LogbookCommand::Pending { vin } => {
+                    let recorded = book.entries(vin)?.iter().map(|e| e.trip_id).collect::<Vec<_>>();
+                    for trip in trips::load_trips(&db, vin, DateTime::<Utc>::MIN_UTC, Utc::now())? {
+                        if !recorded.contains(&trip.id.unwrap_or_default()) {
+                            println!("{} {} - {} {:.1} km", trip.id.unwrap_or_default(), trip.start_time.to_rfc3339(),
+                                trip.end_time.to_rfc3339(), trip.distance_km());
+                        }
+                    }
+                },
+                LogbookCommand::Add { trip_id, purpose, start_address, end_address, note, corrects } => {
+                    let trip = trips::load_trip(&db, *trip_id)?.ok_or(format!("Unknown trip {}", trip_id))?;
+                    let entry = book.add(&trip, *purpose, start_address.clone(), end_address.clone(), note.clone(), *corrects)?;
+                    println!("Recorded trip {} as entry {}", trip_id, entry.seq);
+                },
+                LogbookCommand::Export { vin, format, output } => {
+                    logbook::export(output_writer(output)?, &book.entries(vin)?, *format)?;
+                },
+                LogbookCommand::Verify { vin } => {
+                    match book.verify(vin)? {
+                        Some(violation) => return Err(format!("Logbook of {} {}", vin, violation).into()),
+                        None => println!("Logbook of {} is intact", vin),
+                    }
+                },
+            }
+        },
+        Command::Export { kind, vin, format, range, output } if *kind != export::ExportKind::Position => {
+            let db = history::HistoryDb::open(&cfg_file.history_db)?;
+            let (from, to) = range.bounds();
+            let snapshots = db.snapshots(vin, from, to)?;
+            let tracks = match kind {
+                export::ExportKind::Trips => export::trip_tracks(&trips::load_trips(&db, vin, from, to)?, &snapshots),
+                _ => vec![export::history_track(vin, &snapshots)],
+            };
+            export::write(output_writer(output)?, *format, &[], &tracks)?;
+        },
+        Command::Health { vin } => {
+            let config = &cfg_file.watch.health;
+            let db = history::HistoryDb::open(&cfg_file.history_db)?;
+            let vins = match vin {
+                Some(vin) => vec![vin.to_owned()],
+                None => db.vins()?,
+            };
+
+            let mut count = 0;
+            for vin in vins {
+                let from = Utc::now() - Duration::days(config.voltage_window_days.max(config.stale_days) * 2);
+                let mut snapshots = db.snapshots(&vin, from, Utc::now())?;
+                if snapshots.is_empty() {
+                    // keep the last known snapshot to detect stale data
+                    snapshots.extend(db.latest(&vin)?);
+                }
+                for warning in health::check(config, &snapshots, Utc::now()) {
+                    println!("{} {}", vin, warning);
+                    count += 1;
+                }
+            }
+            if count > 0 {
+                return Err(format!("{} health warnings", count).into());
+            }
+        },
+        Command::InfluxExport { vin, brand: Some(brand), range } => influx_export(cfg_file, vin, brand, range)?,
+        Command::NotifyTest => {
+            let config = cfg_file.notifications.as_ref().ok_or("No notifications section configured")?;
+            let car = psa::model::VehiclesListElement {
+                id: "".to_owned(),
+                vin: "VIN00000000000000".to_owned(),
+                brand: "Test".to_owned(),
+                pictures: vec![],
+                links: Default::default(),
+            };
+            let event = events::VehicleEvent {
+                vin: car.vin.to_owned(),
+                timestamp: Utc::now(),
+                kind: events::VehicleEventKind::ChargeFinished,
+            };
+            let errors = notify::Notifier::new(config).notify(&car, &event);
+            for e in &errors {
+                eprintln!("{}", e);
+            }
+            if !errors.is_empty() {
+                return Err(format!("{} notification sinks failed", errors.len()).into());
+            }
+        },
+        Command::Profiles => {
+            for (name, cfg) in &cfg_file.profiles {
+                let default = if cfg_file.select_profile(None).ok().as_deref() == Some(name) { " (default)" } else { "" };
+                println!("{} [{}] {}{}", name, cfg.brand_code, cfg.api.borrow().client_email, default);
+            }
+        },
+        _ => return Ok(false),
     }
+    Ok(true)
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cfg = check_config()?;
-    let mut client = ApiClient::new(&cfg.api);
-
-    let mut cars = load_cars();
-    if let None = cars {
-        let res = client.connectedcar_list_vehicles()?;
-        cars = Some(res.embedded);
-        save_cars(&cars.as_ref().unwrap());
+    let cli = Cli::parse();
+    let mut cfg_file = config::ConfigFile::from_file(CONFIG_FILE.to_string())?;
+    let command = cli.command.unwrap_or(Command::Status { vin: None });
+
+    if matches!(command, Command::Logbook { .. }) && cfg_file.logbook_key.is_none() {
+        cfg_file.logbook_key = Some(logbook::generate_key()?);
+        cfg_file.to_file(CONFIG_FILE.to_string())?;
+    }
+
+    if run_local(&cfg_file, &command)? {
+        return Ok(());
+    }
+
+    let profiles = if cli.all_profiles {
+        cfg_file.profiles.keys().cloned().collect::<Vec<_>>()
+    } else {
+        vec![cfg_file.select_profile(cli.profile.as_deref())?]
+    };
+
+    for name in &profiles {
+        check_config(name, cfg_file.profiles.entry(name.to_owned()).or_default(), cli.culture.as_deref())?;
+        cfg_file.to_file(CONFIG_FILE.to_string())?;
     }
 
-    let mut input = String::new();
-    println!("Enter VIN of car to get status: ");
-    std::io::stdin().read_line(&mut input)?;
-    let vin = input.trim();
+    match command {
+        Command::Status { vin } => {
+            let vin = match vin {
+                Some(vin) => vin,
+                None => {
+                    let mut input = String::new();
+                    println!("Enter VIN of car to get status: ");
+                    std::io::stdin().read_line(&mut input)?;
+                    input.trim().to_owned()
+                }
+            };
 
-    if let Some(car) = cars.unwrap().vehicles.iter().find(|c| c.vin.eq(vin)) {
-        let res = client.connectedcar_get_vehicle_status(&car.id)?;
-        dbg!(res);
+            let (name, car) = find_car(&cfg_file, &profiles, &vin)?.ok_or("Vehicle not found")?;
+            let cfg = &cfg_file.profiles[&name];
+            let res = ApiClient::new(&cfg.api).connectedcar_get_vehicle_status(&car.id)?;
+            history::HistoryDb::open(&cfg_file.history_db)?.insert(&vin, &res)?;
+            dbg!(res);
+        },
+        Command::Fleet => {
+            let mut reports = BTreeMap::new();
+            for name in &profiles {
+                let cfg = &cfg_file.profiles[name];
+                let cars = get_cars(name, cfg, None, false)?;
+                let report = fleet::fetch_fleet_status(&cfg.api, &cars)?;
+                let db = history::HistoryDb::open(&cfg_file.history_db)?;
+                for (vin, entry) in &report.vehicles {
+                    if let Some(status) = &entry.status {
+                        db.insert(vin, status)?;
+                    }
+                }
+                if report.failed() > 0 {
+                    eprintln!("{}: {} of {} vehicles failed", name, report.failed(), report.vehicles.len());
+                }
+                reports.insert(name.to_owned(), report);
+            }
+
+            if cli.all_profiles {
+                serde_yaml::to_writer(std::io::stdout(), &reports)?;
+            } else if let Some(report) = reports.values().next() {
+                serde_yaml::to_writer(std::io::stdout(), report)?;
+            }
+        },
+        Command::ChargeSchedule { vin, set } => {
+            let (name, car) = find_car(&cfg_file, &profiles, &vin)?.ok_or("Vehicle not found")?;
+            let cfg = &cfg_file.profiles[&name];
+            let mut client = ApiClient::new(&cfg.api);
+            match set {
+                Some(schedule) => {
+                    let res = client.connectedcar_set_charging_schedule(&car.id, schedule)?;
+                    println!("charging {} requested ({})", schedule, res.status.unwrap_or_default());
+                    // the remote action is applied asynchronously
+                    let mut reported = None;
+                    for _ in 0..SCHEDULE_POLLS {
+                        std::thread::sleep(std::time::Duration::from_secs(SCHEDULE_POLL_SECONDS));
+                        let res = client.connectedcar_get_vehicle_status(&car.id)?;
+                        reported = res.charging().map(|c| c.schedule());
+                        if reported == Some(schedule) {
+                            break;
+                        }
+                    }
+                    match reported {
+                        Some(reported) if reported == schedule => println!("vehicle reports charging {}", reported),
+                        Some(reported) => println!("vehicle still reports charging {}, not applied yet", reported),
+                        None => println!("vehicle reports no charging information"),
+                    }
+                },
+                None => {
+                    let res = client.connectedcar_get_vehicle_status(&car.id)?;
+                    let charging = res.charging().ok_or("No charging information reported")?;
+                    println!("schedule: {}", charging.schedule());
+                    println!("mode: {:?}", charging.mode());
+                    println!("status: {}", charging.status);
+                },
+            }
+        },
+        Command::SmartCharge { vin } => {
+            let config = cfg_file.smart_charging.as_ref().ok_or("No smart_charging section configured")?;
+            let goal = config.vehicles.get(&vin).ok_or("No smart charging goal for this vehicle")?;
+            let (name, car) = find_car(&cfg_file, &profiles, &vin)?.ok_or("Vehicle not found")?;
+            let cfg = &cfg_file.profiles[&name];
+            let res = ApiClient::new(&cfg.api).connectedcar_get_vehicle_status(&car.id)?;
+            history::HistoryDb::open(&cfg_file.history_db)?.insert(&vin, &res)?;
+            let snapshot = history::Snapshot::from_status(&vin, &res)?;
+            let prices = smart_charge::load_prices(&config.prices)?;
+            let plan = smart_charge::plan_for(config, &prices, goal, &snapshot, Utc::now())?;
+            serde_yaml::to_writer(std::io::stdout(), &plan)?;
+        },
+        Command::Preconditioning { command } => {
+            let vin = match &command {
+                PreconditioningCommand::Show { vin } | PreconditioningCommand::Push { vin } => vin,
+            };
+            let (name, car) = find_car(&cfg_file, &profiles, vin)?.ok_or("Vehicle not found")?;
+            let cfg = &cfg_file.profiles[&name];
+            let mut client = ApiClient::new(&cfg.api);
+            match &command {
+                PreconditioningCommand::Show { .. } => {
+                    let res = client.connectedcar_get_vehicle_status(&car.id)?;
+                    for program in res.preconditioning_programs() {
+                        let slot = program.slot.map_or("-".to_owned(), |s| s.to_string());
+                        match preconditioning::Schedule::from_program(program) {
+                            Some(s) => println!("{} {} {:?} {}", slot, if s.enabled { "enabled" } else { "disabled" }, s.days, s.time),
+                            None => println!("{} unparsable {:?}", slot, program),
+                        }
+                    }
+                },
+                PreconditioningCommand::Push { .. } => {
+                    let schedules = cfg_file.preconditioning.as_ref()
+                        .and_then(|p| p.vehicles.get(vin))
+                        .ok_or("No preconditioning schedules for this vehicle")?;
+                    let request = psa::model::RemoteRequest::preconditioning_programs(preconditioning::to_programs(&schedules.schedules)?);
+                    client.connectedcar_send_remote(&car.id, &request)?;
+                    println!("{} programs sent", schedules.schedules.len());
+                },
+            }
+        },
+        Command::Cars { refresh } => {
+            for name in &profiles {
+                let cfg = &cfg_file.profiles[name];
+                for car in get_cars(name, cfg, None, refresh)?.vehicles {
+                    println!("{} {} {} ({})", name, car.vin, car.brand, car.id);
+                }
+            }
+        },
+        Command::Watch { mqtt, influx, abrp, dry_run, metrics } => {
+            let mut watcher = watch::Watcher::new(cfg_file.watch.clone());
+            for name in &profiles {
+                let cfg = &cfg_file.profiles[name];
+                let cars = get_cars(name, cfg, None, false)?;
+                watcher.add_account(ApiClient::new(&cfg.api), &cars);
+            }
+            watcher.add_handler(Box::new(watch::LogHandler));
+            let history = history::HistoryDb::open(&cfg_file.history_db)?;
+            watcher.seed_health(&history)?;
+            watcher.add_handler(Box::new(history));
+            if !cfg_file.watch.charge_limit.vehicles.is_empty() {
+                watcher.add_handler(Box::new(charge_limit::ChargeLimiter::new(&cfg_file.watch.charge_limit, dry_run)));
+            }
+            if let Some(config) = &cfg_file.smart_charging {
+                watcher.add_handler(Box::new(smart_charge::SmartCharger::new(config, dry_run)));
+            }
+            if let Some(config) = &cfg_file.preconditioning {
+                watcher.add_handler(Box::new(preconditioning::PreconditioningPlanner::new(config, dry_run)));
+            }
+            if mqtt {
+                let mqtt_config = cfg_file.mqtt.as_ref().ok_or("No mqtt section configured")?;
+                watcher.add_handler(Box::new(mqtt::MqttPublisher::connect(mqtt_config, watcher.command_sender())?));
+            }
+            if let Some(config) = &cfg_file.notifications {
+                watcher.add_handler(Box::new(notify::Notifier::new(config)));
+            }
+            if influx {
+                let influx_config = cfg_file.influx.as_ref().ok_or("No influx section configured")?;
+                watcher.add_handler(Box::new(influx::InfluxWriter::new(influx_config)?));
+            }
+            if abrp {
+                let abrp_config = cfg_file.abrp.as_ref().ok_or("No abrp section configured")?;
+                watcher.add_handler(Box::new(abrp::AbrpSender::new(abrp_config)));
+            }
+            if let Some(addr) = metrics {
+                watcher.add_handler(Box::new(metrics::MetricsExporter::serve(&addr)?));
+            }
+            watcher.run(|| cfg_file.to_file(CONFIG_FILE.to_string()))?;
+        },
+        Command::Export { vin, format, output, .. } => {
+            let (name, car) = find_car(&cfg_file, &profiles, &vin)?.ok_or("Vehicle not found")?;
+            let cfg = &cfg_file.profiles[&name];
+            let res = ApiClient::new(&cfg.api).connectedcar_get_vehicle_status(&car.id)?;
+            history::HistoryDb::open(&cfg_file.history_db)?.insert(&vin, &res)?;
+            let (latitude, longitude) = res.position().ok_or("Vehicle reports no position")?;
+            let waypoint = export::Waypoint {
+                name: vin.to_owned(),
+                point: export::TrackPoint { time: res.last_position.properties.created_at, latitude, longitude },
+            };
+            export::write(output_writer(&output)?, format, &[waypoint], &[])?;
+        },
+        Command::InfluxExport { vin, range, .. } => {
+            // the tag is left out for vehicles unknown to the profiles, e.g. sold ones
+            let brand = find_car(&cfg_file, &profiles, &vin)?.map(|(_, car)| car.brand).unwrap_or_default();
+            influx_export(&cfg_file, &vin, &brand, &range)?;
+        },
+        Command::Serve => {
+            let serve_config = cfg_file.serve.as_ref().ok_or("No serve section configured")?;
+            let mut server = server::ApiServer::new(serve_config, history::HistoryDb::open(&cfg_file.history_db)?)?;
+            for name in &profiles {
+                let cfg = &cfg_file.profiles[name];
+                server.add_account(name, ApiClient::new(&cfg.api), get_cars(name, cfg, None, false)?);
+            }
+            server.run(|| cfg_file.to_file(CONFIG_FILE.to_string()))?;
+        },
+        // handled by run_local
+        _ => (),
     }
-    cfg.to_file(CONFIG_FILE.to_string())?;
+    cfg_file.to_file(CONFIG_FILE.to_string())?;
 
     Ok(())
 }
@@ -0,0 +1,167 @@
+use chrono::{DateTime, Utc};
+use std::{collections::BTreeMap, error::Error, fmt::Write, sync::{atomic::Ordering, Arc, Mutex}, thread};
+use tiny_http::{Header, Response, Server};
+
+use crate::psa::api::{ApiClient, API_METRICS};
+use crate::psa::model::{VehicleStatus, VehiclesListElement};
+use crate::watch::WatchHandler;
+
+type Snapshots = Arc<Mutex<BTreeMap<String, (String, VehicleStatus)>>>;
+
+/// Keeps the last status of each vehicle and serves it on `/metrics` in the
+/// Prometheus text format.
+pub struct MetricsExporter {
+    snapshots: Snapshots,
+}
+
+impl MetricsExporter {
+    pub fn serve(addr: &str) -> Result<MetricsExporter, Box<dyn Error>> {
+        let server = Server::http(addr).map_err(|e| e.to_string())?;
+        let snapshots: Snapshots = Arc::new(Mutex::new(BTreeMap::new()));
+        let shared = snapshots.clone();
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let res = if request.url() == "/metrics" {
+                    let body = render(&shared.lock().unwrap());
+                    Response::from_string(body)
+                        .with_header(Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap())
+                } else {
+                    Response::from_string("not found").with_status_code(404)
+                };
+                if let Err(e) = request.respond(res) {
+                    eprintln!("metrics response failed: {}", e);
+                }
+            }
+        });
+
+        Ok(MetricsExporter { snapshots })
+    }
+}
+
+impl WatchHandler for MetricsExporter {
+    fn on_status(&mut self, _client: &mut ApiClient, car: &VehiclesListElement, status: &VehicleStatus) -> Result<(), Box<dyn Error>> {
+        self.snapshots.lock().unwrap().insert(car.vin.to_owned(), (car.brand.to_owned(), status.clone()));
+        Ok(())
+    }
+}
+
+fn bool_value(value: bool) -> Option<f64> {
+    Some(if value { 1.0 } else { 0.0 })
+}
+
+/// Escapes a label value for the text format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// (metric, help, value at the given time)
+type Gauge = (&'static str, &'static str, fn(&VehicleStatus, DateTime<Utc>) -> Option<f64>);
+
+fn render_vehicles(snapshots: &BTreeMap<String, (String, VehicleStatus)>, now: DateTime<Utc>) -> String {
+    let gauges: [Gauge; 9] = [
+        ("stellantis_battery_level_percent", "State of charge of the traction battery", |s, _| s.soc().map(f64::from)),
+        ("stellantis_autonomy_km", "Remaining electric range", |s, _| s.electric_energy().and_then(|e| e.autonomy).map(f64::from)),
+        ("stellantis_odometer_km", "Odometer mileage", |s, _| Some(s.odometer.mileage as f64)),
+        ("stellantis_battery_voltage_volts", "Voltage of the 12V battery", |s, _| Some(s.battery.voltage as f64)),
+        ("stellantis_air_temperature_celsius", "Outside air temperature", |s, _| Some(s.environment.air.temp as f64)),
+        ("stellantis_charging_rate_kmh", "Charging rate in km of range per hour", |s, _| s.charging().map(|c| c.charging_rate as f64)),
+        ("stellantis_plugged", "Charging cable plugged in", |s, _| bool_value(s.is_plugged())),
+        ("stellantis_moving", "Vehicle is moving", |s, _| bool_value(s.kinetic.moving)),
+        ("stellantis_data_age_seconds", "Age of the vehicle status", |s, now| Some((now - s.updated_at).num_seconds() as f64)),
+    ];
+
+    let mut out = String::new();
+    for (name, help, value) in gauges {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+        for (vin, (brand, status)) in snapshots {
+            if let Some(v) = value(status, now) {
+                let _ = writeln!(out, "{}{{vin=\"{}\",brand=\"{}\"}} {}", name, escape_label(vin), escape_label(brand), v);
+            }
+        }
+    }
+    out
+}
+
+fn render(snapshots: &BTreeMap<String, (String, VehicleStatus)>) -> String {
+    let mut out = render_vehicles(snapshots, Utc::now());
+
+    let counters = [
+        ("stellantis_api_requests_total", "Requests sent to the API", &API_METRICS.requests),
+        ("stellantis_api_errors_total", "Failed API requests", &API_METRICS.errors),
+        ("stellantis_token_refreshes_total", "Successful access token requests", &API_METRICS.token_refreshes),
+    ];
+    for (name, help, counter) in counters {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, counter.load(Ordering::Relaxed));
+    }
+
+    let name = "stellantis_api_request_duration_seconds";
+    let _ = writeln!(out, "# HELP {} Latency of API requests\n# TYPE {} summary", name, name);
+    let _ = writeln!(out, "{}_sum {}", name, API_METRICS.latency_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+    let _ = writeln!(out, "{}_count {}", name, API_METRICS.requests.load(Ordering::Relaxed));
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{start, status, VIN};
+
+    #[test]
+    fn renders_vehicle_gauges() {
+        let mut moving = status(start());
+        moving.kinetic.moving = true;
+        moving.energy.clear();
+        let snapshots = BTreeMap::from([
+            (VIN.to_owned(), ("Peugeot".to_owned(), status(start()))),
+            ("VIN\"2".to_owned(), ("Brand \\ with\nnewline".to_owned(), moving)),
+        ]);
+        let expected = r#"# HELP stellantis_battery_level_percent State of charge of the traction battery
+# TYPE stellantis_battery_level_percent gauge
+stellantis_battery_level_percent{vin="VR3UHZKXZLT123456",brand="Peugeot"} 80
+# HELP stellantis_autonomy_km Remaining electric range
+# TYPE stellantis_autonomy_km gauge
+stellantis_autonomy_km{vin="VR3UHZKXZLT123456",brand="Peugeot"} 240
+# HELP stellantis_odometer_km Odometer mileage
+# TYPE stellantis_odometer_km gauge
+stellantis_odometer_km{vin="VIN\"2",brand="Brand \\ with\nnewline"} 12345.599609375
+stellantis_odometer_km{vin="VR3UHZKXZLT123456",brand="Peugeot"} 12345.599609375
+# HELP stellantis_battery_voltage_volts Voltage of the 12V battery
+# TYPE stellantis_battery_voltage_volts gauge
+stellantis_battery_voltage_volts{vin="VIN\"2",brand="Brand \\ with\nnewline"} 12.600000381469727
+stellantis_battery_voltage_volts{vin="VR3UHZKXZLT123456",brand="Peugeot"} 12.600000381469727
+# HELP stellantis_air_temperature_celsius Outside air temperature
+# TYPE stellantis_air_temperature_celsius gauge
+stellantis_air_temperature_celsius{vin="VIN\"2",brand="Brand \\ with\nnewline"} 12
+stellantis_air_temperature_celsius{vin="VR3UHZKXZLT123456",brand="Peugeot"} 12
+# HELP stellantis_charging_rate_kmh Charging rate in km of range per hour
+# TYPE stellantis_charging_rate_kmh gauge
+stellantis_charging_rate_kmh{vin="VR3UHZKXZLT123456",brand="Peugeot"} 0
+# HELP stellantis_plugged Charging cable plugged in
+# TYPE stellantis_plugged gauge
+stellantis_plugged{vin="VIN\"2",brand="Brand \\ with\nnewline"} 0
+stellantis_plugged{vin="VR3UHZKXZLT123456",brand="Peugeot"} 1
+# HELP stellantis_moving Vehicle is moving
+# TYPE stellantis_moving gauge
+stellantis_moving{vin="VIN\"2",brand="Brand \\ with\nnewline"} 1
+stellantis_moving{vin="VR3UHZKXZLT123456",brand="Peugeot"} 0
+# HELP stellantis_data_age_seconds Age of the vehicle status
+# TYPE stellantis_data_age_seconds gauge
+stellantis_data_age_seconds{vin="VIN\"2",brand="Brand \\ with\nnewline"} 90
+stellantis_data_age_seconds{vin="VR3UHZKXZLT123456",brand="Peugeot"} 90
+"#;
+        // the second vehicle reports no traction battery
+        assert_eq!(render_vehicles(&snapshots, start() + chrono::Duration::seconds(90)), expected);
+    }
+
+    #[test]
+    fn renders_api_counters() {
+        let rendered = render(&BTreeMap::new());
+        for line in ["# TYPE stellantis_api_requests_total counter", "# TYPE stellantis_token_refreshes_total counter",
+                "# TYPE stellantis_api_request_duration_seconds summary"] {
+            assert!(rendered.contains(line), "{} missing", line);
+        }
+        assert!(rendered.lines().any(|l| l.starts_with("stellantis_api_request_duration_seconds_count ")));
+    }
+}
@@ -0,0 +1,314 @@
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::HashSet, error::Error, sync::mpsc::Sender, thread, time::Duration};
+
+use crate::psa::api::ApiClient;
+use crate::psa::model::{RemoteAction, VehicleStatus, VehiclesListElement};
+use crate::watch::{WatchCommand, WatchHandler};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "stellantis-connected-car".to_owned()
+}
+
+fn default_topic_prefix() -> String {
+    "stellantis".to_owned()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_owned()
+}
+
+// (field, name, component, unit, device class)
+type Sensor = (&'static str, &'static str, &'static str, Option<&'static str>, Option<&'static str>);
+
+const SENSORS: [Sensor; 8] = [
+    ("soc", "State of charge", "sensor", Some("%"), Some("battery")),
+    ("autonomy", "Range", "sensor", Some("km"), Some("distance")),
+    ("mileage", "Mileage", "sensor", Some("km"), Some("distance")),
+    ("charging_status", "Charging status", "sensor", None, None),
+    ("charging_rate", "Charging rate", "sensor", Some("km/h"), None),
+    ("battery_voltage", "12V battery", "sensor", Some("V"), Some("voltage")),
+    ("temperature", "Outside temperature", "sensor", Some("°C"), Some("temperature")),
+    ("plugged", "Plugged", "binary_sensor", None, Some("plug")),
+];
+
+// requests buffered while the broker is unreachable, room for the discovery
+// and state messages of a few vehicles
+const REQUEST_QUEUE: usize = 128;
+
+/// Publishes the vehicle status to MQTT with Home Assistant discovery and
+/// forwards messages on the command topics to the watcher.
+pub struct MqttPublisher {
+    client: Client,
+    config: MqttConfig,
+    announced: HashSet<String>,
+}
+
+impl MqttPublisher {
+    pub fn connect(config: &MqttConfig, commands: Sender<WatchCommand>) -> Result<MqttPublisher, Box<dyn Error>> {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+
+        let (client, mut connection) = Client::new(options, REQUEST_QUEUE);
+        let command_topic = format!("{}/+/command", config.topic_prefix);
+        let subscriber = client.clone();
+        let prefix = config.topic_prefix.clone();
+
+        thread::spawn(move || {
+            let mut subscribed = false;
+            for notification in connection.iter() {
+                match notification {
+                    // subscribe on every connect, the session is not persisted
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => subscribed = false,
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        if let Some(command) = parse_command(&prefix, &publish.topic, &publish.payload) {
+                            if commands.send(command).is_err() {
+                                return;
+                            }
+                        }
+                    },
+                    Ok(_) => (),
+                    Err(e) => {
+                        eprintln!("MQTT connection error: {}", e);
+                        thread::sleep(Duration::from_secs(5));
+                    },
+                }
+                // a blocking subscribe deadlocks while the request queue is
+                // full of publishes, this loop is the one draining it
+                if !subscribed {
+                    subscribed = subscriber.try_subscribe(&command_topic, QoS::AtLeastOnce).is_ok();
+                }
+            }
+        });
+
+        Ok(MqttPublisher {
+            client,
+            config: config.clone(),
+            announced: HashSet::new(),
+        })
+    }
+
+    fn announce(&mut self, car: &VehiclesListElement) -> Result<(), Box<dyn Error>> {
+        let prefix = &self.config.topic_prefix;
+        let discovery = &self.config.discovery_prefix;
+        let vin = &car.vin;
+        let device = json!({
+            "identifiers": [vin],
+            "name": format!("{} {}", car.brand, vin),
+            "manufacturer": car.brand,
+        });
+
+        for (field, name, component, unit, device_class) in SENSORS {
+            let mut payload = json!({
+                "name": name,
+                "unique_id": format!("{}_{}", vin, field),
+                "state_topic": format!("{}/{}/state", prefix, vin),
+                "device": device,
+            });
+            if component == "binary_sensor" {
+                payload["value_template"] = json!(format!("{{{{ 'ON' if value_json.{} else 'OFF' }}}}", field));
+            } else {
+                payload["value_template"] = json!(format!("{{{{ value_json.{} }}}}", field));
+            }
+            if let Some(unit) = unit {
+                payload["unit_of_measurement"] = json!(unit);
+            }
+            if let Some(device_class) = device_class {
+                payload["device_class"] = json!(device_class);
+            }
+            self.publish(format!("{}/{}/{}_{}/config", discovery, component, vin, field), payload.to_string(), true)?;
+        }
+
+        let tracker = json!({
+            "name": "Position",
+            "unique_id": format!("{}_position", vin),
+            "json_attributes_topic": format!("{}/{}/position", prefix, vin),
+            "source_type": "gps",
+            "device": device,
+        });
+        self.publish(format!("{}/device_tracker/{}/config", discovery, vin), tracker.to_string(), true)?;
+
+        for action in RemoteAction::ALL {
+            let button = json!({
+                "name": action.name().replace('_', " "),
+                "unique_id": format!("{}_{}", vin, action.name()),
+                "command_topic": format!("{}/{}/command", prefix, vin),
+                "payload_press": action.name(),
+                "device": device,
+            });
+            self.publish(format!("{}/button/{}_{}/config", discovery, vin, action.name()), button.to_string(), true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Queues the message without blocking the watcher, the message is
+    /// dropped if the queue is full, e.g. while the broker is down.
+    fn publish(&self, topic: String, payload: String, retain: bool) -> Result<(), Box<dyn Error>> {
+        if self.client.try_publish(&topic, QoS::AtLeastOnce, retain, payload).is_err() {
+            return Err(format!("MQTT queue full, dropped message to {}", topic).into());
+        }
+        Ok(())
+    }
+}
+
+impl WatchHandler for MqttPublisher {
+    fn on_status(&mut self, _client: &mut ApiClient, car: &VehiclesListElement, status: &VehicleStatus) -> Result<(), Box<dyn Error>> {
+        if !self.announced.contains(&car.vin) {
+            self.announce(car)?;
+            self.announced.insert(car.vin.to_owned());
+        }
+
+        let prefix = &self.config.topic_prefix;
+        let charging = status.charging();
+        let state = json!({
+            "soc": status.soc(),
+            "autonomy": status.electric_energy().and_then(|e| e.autonomy),
+            "mileage": status.odometer.mileage,
+            "plugged": status.is_plugged(),
+            "charging_status": charging.map(|c| c.status.to_owned()),
+            "charging_rate": charging.map(|c| c.charging_rate),
+            "battery_voltage": status.battery.voltage,
+            "temperature": status.environment.air.temp,
+            "updated_at": status.updated_at,
+        });
+        self.publish(format!("{}/{}/state", prefix, car.vin), state.to_string(), true)?;
+
+        if let Some((latitude, longitude)) = status.position() {
+            let position = json!({
+                "latitude": latitude,
+                "longitude": longitude,
+                "gps_accuracy": 10,
+            });
+            self.publish(format!("{}/{}/position", prefix, car.vin), position.to_string(), true)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Maps a message on `<prefix>/<vin>/command` to a watcher command.
+fn parse_command(prefix: &str, topic: &str, payload: &[u8]) -> Option<WatchCommand> {
+    let vin = topic.strip_prefix(prefix)?.strip_prefix('/')?.strip_suffix("/command")?;
+    let name = std::str::from_utf8(payload).ok()?.trim();
+    match RemoteAction::from_name(name) {
+        Some(action) => Some(WatchCommand { vin: vin.to_owned(), action }),
+        None => {
+            eprintln!("{}: unknown command {}", vin, name);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, VIN};
+    use chrono::Utc;
+    use std::{collections::BTreeMap, sync::mpsc};
+
+    fn config(port: u16) -> MqttConfig {
+        MqttConfig {
+            host: "127.0.0.1".to_owned(),
+            port,
+            client_id: "test".to_owned(),
+            username: None,
+            password: None,
+            topic_prefix: default_topic_prefix(),
+            discovery_prefix: default_discovery_prefix(),
+        }
+    }
+
+    #[test]
+    fn publishes_discovery_and_state_to_broker() {
+        let (port, messages) = testutil::mqtt_broker();
+        let (commands, _) = mpsc::channel();
+        let mut publisher = MqttPublisher::connect(&config(port), commands).unwrap();
+        let api = testutil::api_config("http://127.0.0.1:1");
+        let mut client = ApiClient::new(&api);
+        publisher.on_status(&mut client, &testutil::car(), &testutil::status(Utc::now())).unwrap();
+
+        let mut received = BTreeMap::new();
+        while received.len() < SENSORS.len() + 1 + RemoteAction::ALL.len() + 2 {
+            let message = messages.recv_timeout(Duration::from_secs(5)).expect("missing MQTT messages");
+            assert!(message.retain, "{} not retained", message.topic);
+            received.insert(message.topic.clone(), serde_json::from_str::<serde_json::Value>(&message.payload).unwrap());
+        }
+
+        let soc = &received[&format!("homeassistant/sensor/{}_soc/config", VIN)];
+        assert_eq!(soc["state_topic"], format!("stellantis/{}/state", VIN));
+        assert_eq!(soc["unit_of_measurement"], "%");
+        assert_eq!(soc["device_class"], "battery");
+        assert_eq!(soc["device"]["identifiers"][0], VIN);
+
+        let plugged = &received[&format!("homeassistant/binary_sensor/{}_plugged/config", VIN)];
+        assert_eq!(plugged["value_template"], "{{ 'ON' if value_json.plugged else 'OFF' }}");
+
+        let tracker = &received[&format!("homeassistant/device_tracker/{}/config", VIN)];
+        assert_eq!(tracker["json_attributes_topic"], format!("stellantis/{}/position", VIN));
+
+        let button = &received[&format!("homeassistant/button/{}_charge_now/config", VIN)];
+        assert_eq!(button["command_topic"], format!("stellantis/{}/command", VIN));
+        assert_eq!(button["payload_press"], "charge_now");
+
+        let state = &received[&format!("stellantis/{}/state", VIN)];
+        assert_eq!(state["soc"], 80);
+        assert_eq!(state["autonomy"], 240);
+        assert_eq!(state["plugged"], true);
+        assert_eq!(state["charging_status"], "Stopped");
+        assert!((state["battery_voltage"].as_f64().unwrap() - 12.6).abs() < 1e-4);
+
+        let position = &received[&format!("stellantis/{}/position", VIN)];
+        assert!((position["latitude"].as_f64().unwrap() - 48.137).abs() < 1e-4);
+        assert!((position["longitude"].as_f64().unwrap() - 11.575).abs() < 1e-4);
+    }
+
+    #[test]
+    fn drops_messages_while_broker_is_down() {
+        // nothing listens on the port anymore
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (commands, _) = mpsc::channel();
+        let mut publisher = MqttPublisher::connect(&config(port), commands).unwrap();
+        let api = testutil::api_config("http://127.0.0.1:1");
+        let mut client = ApiClient::new(&api);
+
+        let started = std::time::Instant::now();
+        let failed = (0..REQUEST_QUEUE).filter(|_| publisher.on_status(&mut client, &testutil::car(), &testutil::status(Utc::now())).is_err()).count();
+        assert!(failed > 0);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn parses_commands() {
+        let command = parse_command("stellantis", &format!("stellantis/{}/command", VIN), b"charge_now\n").unwrap();
+        assert_eq!(command.vin, VIN);
+        assert_eq!(command.action, RemoteAction::ChargeNow);
+        assert!(parse_command("stellantis", &format!("stellantis/{}/state", VIN), b"charge_now").is_none());
+        assert!(parse_command("stellantis", &format!("stellantis/{}/command", VIN), b"explode").is_none());
+    }
+}
@@ -0,0 +1,319 @@
+use lettre::{message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport, Transport};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::BTreeMap, error::Error, process};
+
+use crate::events::VehicleEvent;
+use crate::psa::api::ApiClient;
+use crate::psa::model::VehiclesListElement;
+use crate::watch::WatchHandler;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkKind {
+    // POSTs the event as JSON
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    Email {
+        host: String,
+        #[serde(default = "default_smtp_port")]
+        port: u16,
+        #[serde(default)]
+        starttls: bool,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+    // url of the topic, e.g. https://ntfy.sh/mycar
+    Ntfy {
+        url: String,
+        #[serde(default)]
+        token: Option<String>,
+        #[serde(default)]
+        priority: Option<u8>,
+    },
+    Gotify {
+        url: String,
+        token: String,
+        #[serde(default)]
+        priority: Option<u8>,
+    },
+    // runs the program, the args are templates as well
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sink {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    // event types sent to this sink, all if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotifyConfig {
+    #[serde(default = "default_title")]
+    pub title: String,
+    #[serde(default = "default_message")]
+    pub message: String,
+    pub sinks: Vec<Sink>,
+}
+
+fn default_title() -> String {
+    "{brand} {vin}".to_owned()
+}
+
+fn default_message() -> String {
+    "{event} at {time}".to_owned()
+}
+
+/// Replaces `{vin}`, `{brand}`, `{event}`, `{type}` and `{time}` in the template.
+pub fn render(template: &str, car: &VehiclesListElement, event: &VehicleEvent) -> String {
+    template
+        .replace("{vin}", &event.vin)
+        .replace("{brand}", &car.brand)
+        .replace("{event}", &event.kind.to_string())
+        .replace("{type}", event.kind.name())
+        .replace("{time}", &event.timestamp.to_rfc3339())
+}
+
+pub struct Notifier {
+    config: NotifyConfig,
+    http: reqwest::blocking::Client,
+}
+
+impl Notifier {
+    pub fn new(config: &NotifyConfig) -> Notifier {
+        Notifier {
+            config: config.clone(),
+            http: reqwest::blocking::Client::new(),
+        }
+    }
+
+    /// Sends the event to every sink accepting it, a failing sink does not
+    /// stop the others. Returns the errors of the failed sinks.
+    pub fn notify(&self, car: &VehiclesListElement, event: &VehicleEvent) -> Vec<Box<dyn Error>> {
+        let title = render(&self.config.title, car, event);
+        let message = render(&self.config.message, car, event);
+
+        self.config.sinks.iter()
+            .filter(|s| s.events.is_empty() || s.events.iter().any(|e| e.eq(event.kind.name())))
+            .filter_map(|s| self.send(&s.kind, car, event, &title, &message).err())
+            .collect()
+    }
+
+    fn send(&self, sink: &SinkKind, car: &VehiclesListElement, event: &VehicleEvent, title: &str, message: &str) -> Result<(), Box<dyn Error>> {
+        match sink {
+            SinkKind::Webhook { url, headers } => {
+                let mut req = self.http.post(url).json(&json!({
+                    "vin": event.vin,
+                    "brand": car.brand,
+                    "title": title,
+                    "message": message,
+                    "event": event,
+                }));
+                for (name, value) in headers {
+                    req = req.header(name, value);
+                }
+                req.send()?.error_for_status()?;
+            },
+            SinkKind::Email { host, port, starttls, username, password, from, to } => {
+                let mut builder = Message::builder()
+                    .from(from.parse::<Mailbox>()?)
+                    .subject(title);
+                for address in to {
+                    builder = builder.to(address.parse::<Mailbox>()?);
+                }
+                let email = builder.body(message.to_owned())?;
+
+                let mut transport = if *starttls {
+                    SmtpTransport::starttls_relay(host)?
+                } else {
+                    SmtpTransport::builder_dangerous(host)
+                }.port(*port);
+                if let Some(username) = username {
+                    transport = transport.credentials(Credentials::new(username.to_owned(), password.clone().unwrap_or_default()));
+                }
+                transport.build().send(&email)?;
+            },
+            SinkKind::Ntfy { url, token, priority } => {
+                // JSON publishing to the server root, headers can not carry non ASCII titles
+                let mut root = reqwest::Url::parse(url.trim_end_matches('/'))?;
+                let topic = root.path_segments().and_then(|mut s| s.next_back()).filter(|t| !t.is_empty())
+                    .ok_or(format!("{} has no topic", url))?.to_owned();
+                root.path_segments_mut().map_err(|_| format!("{} has no topic", url))?.pop();
+                let mut body = json!({
+                    "topic": topic,
+                    "title": title,
+                    "message": message,
+                    "tags": [event.kind.name()],
+                });
+                if let Some(priority) = priority {
+                    body["priority"] = json!(priority);
+                }
+                let mut req = self.http.post(root).json(&body);
+                if let Some(token) = token {
+                    req = req.bearer_auth(token);
+                }
+                req.send()?.error_for_status()?;
+            },
+            SinkKind::Gotify { url, token, priority } => {
+                self.http.post(format!("{}/message", url.trim_end_matches('/')))
+                    .query(&[("token", token)])
+                    .json(&json!({
+                        "title": title,
+                        "message": message,
+                        "priority": priority.unwrap_or(5),
+                    }))
+                    .send()?
+                    .error_for_status()?;
+            },
+            SinkKind::Command { program, args } => {
+                let status = process::Command::new(program)
+                    .args(args.iter().map(|a| render(a, car, event)))
+                    .env("STELLANTIS_VIN", &event.vin)
+                    .env("STELLANTIS_EVENT", event.kind.name())
+                    .env("STELLANTIS_TITLE", title)
+                    .env("STELLANTIS_MESSAGE", message)
+                    .status()?;
+                if !status.success() {
+                    return Err(format!("{} exited with {}", program, status).into());
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+impl WatchHandler for Notifier {
+    fn on_event(&mut self, _client: &mut ApiClient, car: &VehiclesListElement, event: &VehicleEvent) -> Result<(), Box<dyn Error>> {
+        let errors = self.notify(car, event);
+        match errors.len() {
+            0 => Ok(()),
+            _ => Err(errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(", ").into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::VehicleEventKind;
+    use crate::testutil::{car, http_server, smtp_server, start, VIN};
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn event() -> VehicleEvent {
+        VehicleEvent { vin: VIN.to_owned(), timestamp: start(), kind: VehicleEventKind::ChargeFinished }
+    }
+
+    fn notifier(sinks: Vec<SinkKind>) -> Notifier {
+        Notifier::new(&NotifyConfig {
+            title: "{brand} {vin} ⚡".to_owned(),
+            message: "{event} at {time}".to_owned(),
+            sinks: sinks.into_iter().map(|kind| Sink { kind, events: vec![] }).collect(),
+        })
+    }
+
+    #[test]
+    fn posts_event_to_webhook() {
+        let (url, requests) = http_server(|_| (200, "".to_owned()));
+        let headers = BTreeMap::from([("X-Api-Key".to_owned(), "secret".to_owned())]);
+        let errors = notifier(vec![SinkKind::Webhook { url: format!("{}/hook", url), headers }]).notify(&car(), &event());
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let request = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/hook"));
+        assert_eq!(request.headers["x-api-key"], "secret");
+        let body = request.json();
+        assert_eq!(body["vin"], VIN);
+        assert_eq!(body["title"], format!("Peugeot {} ⚡", VIN));
+        assert_eq!(body["message"], "charge finished at 2024-03-01T08:00:00+00:00");
+        assert_eq!(body["event"]["type"], "charge_finished");
+    }
+
+    #[test]
+    fn publishes_non_ascii_title_to_ntfy() {
+        let (url, requests) = http_server(|_| (200, "{}".to_owned()));
+        let sink = SinkKind::Ntfy { url: format!("{}/mycar", url), token: Some("tk".to_owned()), priority: Some(4) };
+        let errors = notifier(vec![sink]).notify(&car(), &event());
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let request = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(request.path, "/");
+        assert_eq!(request.headers["authorization"], "Bearer tk");
+        assert_eq!(request.json(), json!({
+            "topic": "mycar",
+            "title": format!("Peugeot {} ⚡", VIN),
+            "message": "charge finished at 2024-03-01T08:00:00+00:00",
+            "tags": ["charge_finished"],
+            "priority": 4,
+        }));
+    }
+
+    #[test]
+    fn posts_message_to_gotify() {
+        let (url, requests) = http_server(|_| (200, "{}".to_owned()));
+        let sink = SinkKind::Gotify { url: format!("{}/", url), token: "app".to_owned(), priority: None };
+        assert!(notifier(vec![sink]).notify(&car(), &event()).is_empty());
+
+        let request = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(request.path, "/message?token=app");
+        assert_eq!(request.json()["priority"], 5);
+        assert_eq!(request.json()["title"], format!("Peugeot {} ⚡", VIN));
+    }
+
+    #[test]
+    fn sends_mail_with_encoded_subject() {
+        let (port, mails) = smtp_server();
+        let sink = SinkKind::Email {
+            host: "127.0.0.1".to_owned(),
+            port,
+            starttls: false,
+            username: None,
+            password: None,
+            from: "Car <car@example.com>".to_owned(),
+            to: vec!["me@example.com".to_owned(), "you@example.com".to_owned()],
+        };
+        let errors = notifier(vec![sink]).notify(&car(), &event());
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let mail = mails.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(mail.from, "car@example.com");
+        assert_eq!(mail.to, vec!["me@example.com", "you@example.com"]);
+        assert!(mail.data.contains(&format!("Subject: Peugeot {} =?utf-8?b?4pqh?=\r\n", VIN)), "{}", mail.data);
+        assert!(mail.data.contains("charge finished at 2024-03-01T08:00:00+00:00"), "{}", mail.data);
+    }
+
+    #[test]
+    fn filters_events_and_continues_after_failed_sink() {
+        let (url, requests) = http_server(|r| (if r.path == "/broken" { 500 } else { 200 }, "".to_owned()));
+        let mut notifier = notifier(vec![
+            SinkKind::Webhook { url: format!("{}/broken", url), headers: BTreeMap::new() },
+            SinkKind::Webhook { url: format!("{}/ignition", url), headers: BTreeMap::new() },
+            SinkKind::Webhook { url: format!("{}/all", url), headers: BTreeMap::new() },
+        ]);
+        notifier.config.sinks[1].events = vec!["ignition_on".to_owned()];
+
+        assert_eq!(notifier.notify(&car(), &event()).len(), 1);
+        assert_eq!(requests.recv_timeout(TIMEOUT).unwrap().path, "/broken");
+        assert_eq!(requests.recv_timeout(TIMEOUT).unwrap().path, "/all");
+        assert!(requests.try_recv().is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, TimeZone, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, error::Error};
+
+use crate::psa::api::ApiClient;
+use crate::psa::model::{format_time_of_day, parse_time_of_day, PreconditioningProgram, ProgramOccurence, RemoteAction, VehicleStatus, VehiclesListElement};
+use crate::watch::WatchHandler;
+
+// program slots supported by the vehicles
+pub const MAX_PROGRAMS: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub days: Vec<Weekday>,
+    // local departure time
+    pub time: NaiveTime,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Schedule {
+    pub fn from_program(program: &PreconditioningProgram) -> Option<Schedule> {
+        Some(Schedule {
+            days: program.occurence.day.iter().map(|d| d.parse().ok()).collect::<Option<Vec<Weekday>>>()?,
+            time: parse_time_of_day(&program.start)?,
+            enabled: program.enabled,
+        })
+    }
+
+    pub fn to_program(&self, slot: u32) -> PreconditioningProgram {
+        PreconditioningProgram {
+            enabled: self.enabled,
+            slot: Some(slot),
+            recurrence: "Daily".to_owned(),
+            start: format_time_of_day(self.time),
+            occurence: ProgramOccurence {
+                // chrono formats the short english names used by the API
+                day: self.days.iter().map(|d| d.to_string()).collect(),
+            },
+        }
+    }
+
+    /// Next departure of the schedule at or after the given time.
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if !self.enabled {
+            return None;
+        }
+        let local = time.with_timezone(&Local);
+        (0..8).map(|d| local.date_naive() + Duration::days(d))
+            .filter(|date| self.days.contains(&date.weekday()))
+            .filter_map(|date| Local.from_local_datetime(&date.and_time(self.time)).earliest())
+            .map(|at| at.with_timezone(&Utc))
+            .find(|at| *at >= time)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlannerConfig {
+    // cabin temperature the lead time is computed for
+    pub target_temp: f32,
+    // no preconditioning if the outside temperature is this close to the target
+    pub tolerance: f32,
+    pub min_minutes: u32,
+    pub max_minutes: u32,
+    pub minutes_per_degree: f32,
+}
+
+impl Default for PlannerConfig {
+    fn default() -> Self {
+        PlannerConfig {
+            target_temp: 21.0,
+            tolerance: 3.0,
+            min_minutes: 10,
+            max_minutes: 30,
+            minutes_per_degree: 1.0,
+        }
+    }
+}
+
+impl PlannerConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_minutes < self.min_minutes {
+            return Err(format!("preconditioning max_minutes {} is below min_minutes {}", self.max_minutes, self.min_minutes));
+        }
+        Ok(())
+    }
+
+    /// Time preconditioning has to start before departure at the outside
+    /// temperature, None if it is not needed.
+    pub fn lead_time(&self, outside_temp: f32) -> Option<Duration> {
+        let diff = (self.target_temp - outside_temp).abs();
+        if diff < self.tolerance {
+            return None;
+        }
+        let minutes = (self.min_minutes as f32 + diff * self.minutes_per_degree)
+            .clamp(self.min_minutes as f32, self.max_minutes as f32);
+        Some(Duration::minutes(minutes.round() as i64))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VehicleSchedules {
+    pub schedules: Vec<Schedule>,
+    // start preconditioning from the watch mode instead of the vehicle programs
+    #[serde(default)]
+    pub local: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PreconditioningConfig {
+    #[serde(default)]
+    pub planner: PlannerConfig,
+    // schedules by VIN
+    #[serde(default)]
+    pub vehicles: BTreeMap<String, VehicleSchedules>,
+}
+
+/// Converts the schedules into programs for the vehicle slots.
+pub fn to_programs(schedules: &[Schedule]) -> Result<Vec<PreconditioningProgram>, Box<dyn Error>> {
+    if schedules.len() > MAX_PROGRAMS {
+        return Err(format!("At most {} preconditioning programs are supported", MAX_PROGRAMS).into());
+    }
+    Ok(schedules.iter().enumerate().map(|(i, s)| s.to_program(i as u32 + 1)).collect())
+}
+
+/// Starts preconditioning before the next departure of vehicles with local
+/// schedules, the lead time depends on the outside temperature.
+pub struct PreconditioningPlanner {
+    config: PreconditioningConfig,
+    dry_run: bool,
+    // last outside temperature and the departure preconditioning was started for
+    temperatures: BTreeMap<String, f32>,
+    started: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl PreconditioningPlanner {
+    pub fn new(config: &PreconditioningConfig, dry_run: bool) -> PreconditioningPlanner {
+        PreconditioningPlanner {
+            config: config.clone(),
+            dry_run,
+            temperatures: BTreeMap::new(),
+            started: BTreeMap::new(),
+        }
+    }
+
+    fn next_departure(&self, vin: &str, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.config.vehicles.get(vin)
+            .filter(|v| v.local)?
+            .schedules.iter()
+            .filter_map(|s| s.next_after(time))
+            .min()
+    }
+
+    /// Starts preconditioning once per departure when the lead time before
+    /// it is reached at `now`.
+    fn start_due(&mut self, client: &mut ApiClient, car: &VehiclesListElement, status: &VehicleStatus, now: DateTime<Utc>) -> Result<(), Box<dyn Error>> {
+        let departure = match self.next_departure(&car.vin, now) {
+            Some(departure) => departure,
+            None => return Ok(()),
+        };
+        let temp = status.environment.air.temp;
+        self.temperatures.insert(car.vin.to_owned(), temp);
+
+        let lead = match self.config.planner.lead_time(temp) {
+            Some(lead) => lead,
+            None => return Ok(()),
+        };
+        if now < departure - lead || self.started.get(&car.vin) == Some(&departure) {
+            return Ok(());
+        }
+        if self.dry_run {
+            println!("{} would start preconditioning for {} at {}°C", car.vin, departure.with_timezone(&Local), temp);
+        } else {
+            println!("{} starting preconditioning for {} at {}°C", car.vin, departure.with_timezone(&Local), temp);
+            client.connectedcar_remote_action(&car.id, RemoteAction::PreconditioningOn)?;
+        }
+        self.started.insert(car.vin.to_owned(), departure);
+        Ok(())
+    }
+}
+
+impl WatchHandler for PreconditioningPlanner {
+    fn on_status(&mut self, client: &mut ApiClient, car: &VehiclesListElement, status: &VehicleStatus) -> Result<(), Box<dyn Error>> {
+        self.start_due(client, car, status, Utc::now())
+    }
+
+    fn next_wakeup(&self, car: &VehiclesListElement) -> Option<DateTime<Utc>> {
+        let departure = self.next_departure(&car.vin, Utc::now())?;
+        let lead = self.temperatures.get(&car.vin)
+            .and_then(|t| self.config.planner.lead_time(*t))
+            .unwrap_or_else(|| Duration::minutes(self.config.planner.max_minutes as i64));
+        Some(departure - lead)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, car, http_server, VIN};
+    use chrono::NaiveDate;
+
+    fn local(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        let date = NaiveDate::from_ymd_opt(2024, 3, day).unwrap();
+        Local.from_local_datetime(&date.and_hms_opt(hour, minute, 0).unwrap()).earliest().unwrap().with_timezone(&Utc)
+    }
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn schedule(days: &[Weekday], hour: u32) -> Schedule {
+        Schedule { days: days.to_vec(), time: time(hour, 0), enabled: true }
+    }
+
+    #[test]
+    fn converts_schedules_to_programs_and_back() {
+        let schedule = Schedule { days: vec![Weekday::Mon, Weekday::Fri], time: time(7, 30), enabled: false };
+        let program = schedule.to_program(2);
+        assert_eq!(program.slot, Some(2));
+        assert_eq!(program.start, "PT7H30M");
+        assert_eq!(program.occurence.day, vec!["Mon", "Fri"]);
+        assert_eq!(Schedule::from_program(&program), Some(schedule.clone()));
+
+        let mut invalid = program.clone();
+        invalid.occurence.day.push("Someday".to_owned());
+        assert!(Schedule::from_program(&invalid).is_none());
+
+        let programs = to_programs(&[schedule.clone(), schedule.clone()]).unwrap();
+        assert_eq!(programs.iter().map(|p| p.slot).collect::<Vec<_>>(), vec![Some(1), Some(2)]);
+        assert!(to_programs(&vec![schedule; MAX_PROGRAMS + 1]).is_err());
+    }
+
+    #[test]
+    fn finds_next_departure() {
+        // 2024-03-04 is a Monday
+        let workdays = schedule(&[Weekday::Mon, Weekday::Wed], 7);
+        assert_eq!(workdays.next_after(local(4, 6, 0)), Some(local(4, 7, 0)));
+        assert_eq!(workdays.next_after(local(4, 7, 0)), Some(local(4, 7, 0)));
+        assert_eq!(workdays.next_after(local(4, 7, 1)), Some(local(6, 7, 0)));
+        // over the end of the week
+        assert_eq!(workdays.next_after(local(9, 12, 0)), Some(local(11, 7, 0)));
+        assert_eq!(schedule(&[Weekday::Mon], 7).next_after(local(4, 8, 0)), Some(local(11, 7, 0)));
+
+        assert_eq!(schedule(&[], 7).next_after(local(4, 6, 0)), None);
+        assert_eq!(Schedule { enabled: false, ..workdays }.next_after(local(4, 6, 0)), None);
+    }
+
+    #[test]
+    fn computes_lead_time_from_outside_temperature() {
+        let config = PlannerConfig::default();
+        assert_eq!(config.lead_time(20.0), None);
+        assert_eq!(config.lead_time(18.0), Some(Duration::minutes(13)));
+        assert_eq!(config.lead_time(15.0), Some(Duration::minutes(16)));
+        assert_eq!(config.lead_time(30.0), Some(Duration::minutes(19)));
+        assert_eq!(config.lead_time(-5.0), Some(Duration::minutes(30)));
+
+        assert!(config.validate().is_ok());
+        assert!(PlannerConfig { min_minutes: 40, ..config }.validate().is_err());
+    }
+
+    #[test]
+    fn starts_preconditioning_once_per_departure() {
+        let (url, requests) = http_server(|_| (200, "{\"remoteActionId\": \"1\"}".to_owned()));
+        let api = testutil::api_config(&url);
+        let mut client = ApiClient::new(&api);
+        let config = PreconditioningConfig {
+            planner: PlannerConfig::default(),
+            vehicles: BTreeMap::from([(VIN.to_owned(), VehicleSchedules {
+                schedules: vec![schedule(&[Weekday::Mon, Weekday::Tue], 7)],
+                local: true,
+            })]),
+        };
+        let mut planner = PreconditioningPlanner::new(&config, false);
+        // 12°C outside, 19 minutes ahead
+        let status = testutil::status(local(4, 6, 0));
+
+        for (now, starts) in [(local(4, 6, 40), 0), (local(4, 6, 41), 1), (local(4, 6, 50), 0), (local(4, 7, 30), 0), (local(5, 6, 45), 1)] {
+            planner.start_due(&mut client, &car(), &status, now).unwrap();
+            let sent = requests.try_iter().collect::<Vec<_>>();
+            assert_eq!(sent.len(), starts, "{}", now);
+            assert!(sent.iter().all(|r| r.json()["preconditioning"]["airConditioning"]["immediate"] == true), "{}", now);
+        }
+        assert_eq!(planner.started[VIN], local(5, 7, 0));
+    }
+
+    #[test]
+    fn ignores_vehicles_without_local_schedules() {
+        let config = PreconditioningConfig {
+            planner: PlannerConfig::default(),
+            vehicles: BTreeMap::from([(VIN.to_owned(), VehicleSchedules { schedules: vec![schedule(&[Weekday::Mon], 7)], local: false })]),
+        };
+        let api = testutil::api_config("http://127.0.0.1:1");
+        let mut client = ApiClient::new(&api);
+        let mut planner = PreconditioningPlanner::new(&config, false);
+        planner.start_due(&mut client, &car(), &testutil::status(local(4, 6, 0)), local(4, 6, 50)).unwrap();
+        assert!(planner.started.is_empty());
+    }
+}
@@ -1,12 +1,37 @@
 use reqwest::header::{USER_AGENT, CONTENT_TYPE};
-use serde::de::DeserializeOwned;
-use std::{collections::HashMap, fmt, error::Error, cell::RefCell};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, fmt, error::Error, cell::RefCell, sync::atomic::{AtomicU64, Ordering}, time::Instant};
 use chrono::{Duration, Utc};
 
 use super::model::*;
 
 const APP_VERSION: &str = "1.33.0";
 
+/// Health counters of the API client, shared by all clients of the process.
+pub struct ApiMetrics {
+    pub requests: AtomicU64,
+    pub errors: AtomicU64,
+    pub token_refreshes: AtomicU64,
+    pub latency_micros: AtomicU64,
+}
+
+pub static API_METRICS: ApiMetrics = ApiMetrics {
+    requests: AtomicU64::new(0),
+    errors: AtomicU64::new(0),
+    token_refreshes: AtomicU64::new(0),
+    latency_micros: AtomicU64::new(0),
+};
+
+impl ApiMetrics {
+    fn record(&self, started: Instant, res: &reqwest::Result<reqwest::blocking::Response>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.latency_micros.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        if !res.as_ref().is_ok_and(|r| r.status().is_success()) {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ApiError {
     pub message: String
@@ -104,10 +129,15 @@ pub struct ApiClient<'a> {
 impl<'a> ApiClient<'a> {
     pub fn new(config: &'a RefCell<ApiConfig>) -> ApiClient<'a> {
         ApiClient {
-            config: config.clone()
+            config
         }
     }
 
+    /// Current config including the refreshed tokens.
+    pub fn config(&self) -> ApiConfig {
+        self.config.borrow().clone()
+    }
+
     pub fn token_request(&mut self) -> Result<(), Box<dyn Error>> {
 
         let mut config = self.config.borrow_mut();
@@ -138,15 +168,19 @@ impl<'a> ApiClient<'a> {
         };
 
         let client = reqwest::blocking::Client::new();
+        let started = Instant::now();
         let res = client.post(config.oauth_url.to_owned())
             .form(&req)
             .basic_auth(config.client_id.to_owned(), Some(config.client_secret.to_owned()))
             .header("Source-Agent", "App-Android")
             .header("Version", APP_VERSION)
             .header(USER_AGENT, "okhttp/4.8.0")
-            .send()?;
+            .send();
+        API_METRICS.record(started, &res);
+        let res = res?;
 
         let auth_response = res.json::<TokenResponse>()?;
+        API_METRICS.token_refreshes.fetch_add(1, Ordering::Relaxed);
         config.refresh_token = auth_response.refresh_token.to_owned();
         config.access_token = auth_response.access_token.to_owned();
         config.token_expires = Some(Utc::now() + Duration::seconds(auth_response.expires_in as i64));
@@ -174,10 +208,13 @@ impl<'a> ApiClient<'a> {
 
         let url = reqwest::Url::parse_with_params(format!("{}/{}", config.host_api_prod, path).as_str(), &params)?;
         let client = reqwest::blocking::Client::new();
+        let started = Instant::now();
         let res = client.get(url)
             .bearer_auth(config.access_token.to_owned())
             .header("x-introspect-realm", config.realm.to_owned())
-            .send()?;
+            .send();
+        API_METRICS.record(started, &res);
+        let res = res?;
 
         Ok(res.json::<ListResponse<T>>()?)
     }
@@ -192,14 +229,42 @@ impl<'a> ApiClient<'a> {
 
         let url = reqwest::Url::parse_with_params(format!("{}/{}", config.host_api_prod, path).as_str(), &params)?;
         let client = reqwest::blocking::Client::new();
+        let started = Instant::now();
         let res = client.get(url)
             .bearer_auth(config.access_token.to_owned())
             .header("x-introspect-realm", config.realm.to_owned())
-            .send()?;
+            .send();
+        API_METRICS.record(started, &res);
+        let res = res?;
 
         Ok(res.json::<T>()?)
     }
 
+    fn post_item<B, T>(&mut self, path: String, body: &B) -> Result<T, Box<dyn Error>> where B: Serialize, T: DeserializeOwned {
+        self.check_token()?;
+        let config = self.config.borrow();
+
+        let params = [
+            ("client_id", config.client_id.to_owned()),
+        ];
+
+        let url = reqwest::Url::parse_with_params(format!("{}/{}", config.host_api_prod, path).as_str(), &params)?;
+        let client = reqwest::blocking::Client::new();
+        let started = Instant::now();
+        let res = client.post(url)
+            .bearer_auth(config.access_token.to_owned())
+            .header("x-introspect-realm", config.realm.to_owned())
+            .json(body)
+            .send();
+        API_METRICS.record(started, &res);
+        let res = res?;
+
+        if !res.status().is_success() {
+            return Err(Box::new(ApiError { message: format!("POST {} failed with {}: {}", path, res.status(), res.text().unwrap_or_default())}));
+        }
+        Ok(res.json::<T>()?)
+    }
+
     pub fn connectedcar_list_vehicles(&mut self) -> Result<ListResponse<VehiclesList>, Box<dyn Error>> {
         Ok(self.get_list::<VehiclesList>("connectedcar/v4/user/vehicles".to_string())?)
     }
@@ -208,4 +273,20 @@ impl<'a> ApiClient<'a> {
         Ok(self.get_item::<VehicleStatus>(format!("connectedcar/v4/user/vehicles/{}/status", id))?)
     }
 
+    pub fn connectedcar_send_remote(&mut self, id: &String, req: &RemoteRequest) -> Result<RemoteResponse, Box<dyn Error>> {
+        let callback_id = self.config.borrow().remote_callback_id.to_owned();
+        if callback_id.is_empty() {
+            return Err(Box::new(ApiError { message: "No remote_callback_id configured".to_owned()}));
+        }
+        self.post_item::<RemoteRequest, RemoteResponse>(format!("connectedcar/v4/user/vehicles/{}/callbacks/{}/remotes", id, callback_id), req)
+    }
+
+    pub fn connectedcar_remote_action(&mut self, id: &String, action: RemoteAction) -> Result<RemoteResponse, Box<dyn Error>> {
+        self.connectedcar_send_remote(id, &action.to_request())
+    }
+
+    pub fn connectedcar_set_charging_schedule(&mut self, id: &String, schedule: ChargingSchedule) -> Result<RemoteResponse, Box<dyn Error>> {
+        self.connectedcar_send_remote(id, &schedule.to_request())
+    }
+
 }
\ No newline at end of file
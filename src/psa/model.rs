@@ -1,7 +1,9 @@
 pub mod auth;
 pub mod config;
 pub mod connectedcar;
+pub mod remote;
 
 pub use auth::*;
 pub use config::*;
 pub use connectedcar::*;
+pub use remote::*;
@@ -1,7 +1,7 @@
 use chrono::{serde::ts_seconds_option, DateTime, Utc};
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ApiConfig {
     pub realm: String,
     pub oauth_url: String,
@@ -14,7 +14,10 @@ pub struct ApiConfig {
     pub access_token: String,
     #[serde(default)]
     #[serde(with = "ts_seconds_option")]
-    pub token_expires: Option<DateTime<Utc>>
+    pub token_expires: Option<DateTime<Utc>>,
+    // callback registered for remote actions
+    #[serde(default)]
+    pub remote_callback_id: String,
 }
 
 impl Default for ApiConfig {
@@ -30,6 +33,7 @@ impl Default for ApiConfig {
             refresh_token: "".to_string(),
             access_token: "".to_string(),
             token_expires: None,
+            remote_callback_id: "".to_string(),
         }
     }
 }
\ No newline at end of file
@@ -1,16 +1,18 @@
 use std::collections::HashMap;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveTime, Timelike, Utc};
 use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
 
-#[derive(Debug, Deserialize, Serialize)]
+use super::ChargingSchedule;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LinkElement {
     pub href: String,
     pub templated: Option<bool>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ListResponse<T> {
     #[serde(alias = "_links")]
@@ -22,13 +24,13 @@ pub struct ListResponse<T> {
     pub total_page: u32,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehiclesList {
     pub vehicles: Vec<VehiclesListElement>
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehiclesListElement {
     pub id: String,
@@ -39,7 +41,7 @@ pub struct VehiclesListElement {
     pub links: HashMap<String, LinkElement>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehicleStatus {
     pub created_at: DateTime<Utc>,
@@ -60,7 +62,58 @@ pub struct VehicleStatus {
     pub energy: Vec<VehicleEnergy>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl VehicleStatus {
+    pub fn electric_energy(&self) -> Option<&VehicleEnergy> {
+        self.energy.iter()
+            .chain(self.energies.iter())
+            .find(|e| e._type.eq("Electric"))
+    }
+
+    pub fn charging(&self) -> Option<&EnergyCharging> {
+        let energy = self.electric_energy()?;
+        match &energy.extension {
+            Some(extension) => Some(&extension.electric.charging),
+            None => energy.charging.as_ref(),
+        }
+    }
+
+    /// State of charge of the traction battery in percent.
+    pub fn soc(&self) -> Option<u32> {
+        self.electric_energy().map(|e| e.level)
+    }
+
+    pub fn is_charging(&self) -> bool {
+        self.charging().is_some_and(|c| c.status.eq("InProgress"))
+    }
+
+    pub fn is_plugged(&self) -> bool {
+        self.charging().is_some_and(|c| c.plugged)
+    }
+
+    pub fn ignition_on(&self) -> bool {
+        !self.ignition._type.eq("Stop")
+    }
+
+    /// Preconditioning programs, reported under either spelling.
+    pub fn preconditioning_programs(&self) -> &[PreconditioningProgram] {
+        let programs = &self.preconditioning.air_conditioning.programs;
+        if programs.is_empty() {
+            &self.preconditionning.air_conditioning.programs
+        } else {
+            programs
+        }
+    }
+
+    /// Last known position as latitude and longitude.
+    pub fn position(&self) -> Option<(f64, f64)> {
+        match self.last_position.geometry.coordinates[..] {
+            [lon, lat, ..] => Some((lat as f64, lon as f64)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehiclePosition {
     #[serde(alias = "type")]
@@ -69,7 +122,7 @@ pub struct VehiclePosition {
     pub properties: PositionProperties
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PositionGeometry {
     #[serde(alias = "type")]
@@ -77,7 +130,7 @@ pub struct PositionGeometry {
     pub coordinates: Vec<f32>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PositionProperties {
     #[serde(alias = "type")]
@@ -85,7 +138,7 @@ pub struct PositionProperties {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehicleIgnition {
     #[serde(alias = "type")]
@@ -93,7 +146,7 @@ pub struct VehicleIgnition {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehicleBattery {
     #[serde(alias = "type")]
@@ -101,7 +154,7 @@ pub struct VehicleBattery {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehiclePrivacy {
     #[serde(alias = "type")]
@@ -109,7 +162,7 @@ pub struct VehiclePrivacy {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehicleService {
     #[serde(alias = "type")]
@@ -117,57 +170,106 @@ pub struct VehicleService {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehicleEnvironment {
     pub luminosity: EnvironmentLuminosity,
     pub air: EnvironmentAir,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EnvironmentLuminosity {
     pub day: bool,
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EnvironmentAir {
     pub temp: f32,
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehicleOdometer {
     pub mileage: f32,
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehicleKinetic {
     pub moving: bool,
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehiclePreconditioning {
     pub air_conditioning: AirConditioning,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AirConditioning {
     pub status: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub programs: Vec<PreconditioningProgram>,
 }
 
+// only enabled and start are always sent, the others vary between vehicles
 #[skip_serializing_none]
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreconditioningProgram {
+    pub enabled: bool,
+    #[serde(default)]
+    pub slot: Option<u32>,
+    #[serde(default)]
+    pub recurrence: String,
+    // time of day as duration, e.g. PT7H30M
+    pub start: String,
+    #[serde(default)]
+    pub occurence: ProgramOccurence,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProgramOccurence {
+    // short english day names, e.g. Mon
+    pub day: Vec<String>,
+}
+
+/// Parses a time of day sent as duration since midnight, e.g. PT22H30M.
+/// Hours beyond 23 and minutes beyond 59 are rejected.
+pub fn parse_time_of_day(value: &str) -> Option<NaiveTime> {
+    // a bare PT has no components at all
+    let mut rest = value.strip_prefix("PT").filter(|r| !r.is_empty())?;
+    let (mut hours, mut minutes) = (0, 0);
+    if let Some((h, r)) = rest.split_once('H') {
+        hours = h.parse().ok()?;
+        rest = r;
+    }
+    if let Some((m, r)) = rest.split_once('M') {
+        minutes = m.parse().ok()?;
+        rest = r;
+    }
+    if !rest.is_empty() {
+        return None;
+    }
+    NaiveTime::from_hms_opt(hours, minutes, 0)
+}
+
+pub fn format_time_of_day(time: NaiveTime) -> String {
+    format!("PT{}H{}M", time.hour(), time.minute())
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehicleEnergy {
     pub created_at: DateTime<Utc>,
@@ -180,26 +282,26 @@ pub struct VehicleEnergy {
     pub charging: Option<EnergyCharging>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EnergyExtension {
     pub electric: EnergyElectric,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EnergyElectric {
     pub battery: EnergyBattery,
     pub charging: EnergyCharging,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EnergyBattery {
     pub load: EnergyBatteryLoad,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EnergyBatteryLoad {
     pub created_at: DateTime<Utc>,
@@ -207,7 +309,7 @@ pub struct EnergyBatteryLoad {
     pub residual: u32,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EnergyCharging {
     pub plugged: bool,
@@ -217,3 +319,61 @@ pub struct EnergyCharging {
     pub charging_mode: String,
     pub next_delayed_time: String,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ChargingMode {
+    No,
+    Slow,
+    Quick,
+    Unknown,
+}
+
+impl EnergyCharging {
+    pub fn mode(&self) -> ChargingMode {
+        match self.charging_mode.as_str() {
+            "No" => ChargingMode::No,
+            "Slow" => ChargingMode::Slow,
+            "Quick" => ChargingMode::Quick,
+            _ => ChargingMode::Unknown,
+        }
+    }
+
+    /// Local start time of delayed charging.
+    pub fn delayed_start(&self) -> Option<NaiveTime> {
+        parse_time_of_day(&self.next_delayed_time)
+    }
+
+    /// Schedule as set on the vehicle, no delay is reported as `PT0S`.
+    pub fn schedule(&self) -> ChargingSchedule {
+        match self.delayed_start() {
+            Some(start) => ChargingSchedule::Delayed(start),
+            None => ChargingSchedule::Immediate,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, start};
+    use serde_json::json;
+
+    #[test]
+    fn parses_status_with_partial_programs() {
+        let mut value = serde_json::to_value(testutil::status(start())).unwrap();
+        value["preconditionning"]["airConditioning"]["programs"] = json!([
+            { "enabled": true, "slot": 1, "recurrence": "Daily", "start": "PT7H30M", "occurence": { "day": ["Mon", "Fri"] } },
+            { "enabled": false, "start": "PT18H" },
+        ]);
+        let status: VehicleStatus = serde_json::from_value(value).unwrap();
+
+        let programs = status.preconditioning_programs();
+        assert_eq!(programs.len(), 2);
+        assert_eq!(programs[0].slot, Some(1));
+        assert_eq!(programs[0].occurence.day, vec!["Mon", "Fri"]);
+        assert_eq!(programs[1].slot, None);
+        assert!(programs[1].recurrence.is_empty());
+        assert!(programs[1].occurence.day.is_empty());
+        assert_eq!(parse_time_of_day(&programs[1].start), NaiveTime::from_hms_opt(18, 0, 0));
+    }
+}
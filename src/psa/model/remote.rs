@@ -0,0 +1,218 @@
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use std::{fmt, str::FromStr};
+
+use super::{format_time_of_day, parse_time_of_day, PreconditioningProgram};
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteRequest {
+    pub label: Option<String>,
+    pub preconditioning: Option<RemotePreconditioning>,
+    pub charging: Option<RemoteCharging>,
+    pub door: Option<RemoteState>,
+    pub horn: Option<RemoteState>,
+    pub lights: Option<RemoteLights>,
+}
+
+impl RemoteRequest {
+    /// Replaces the preconditioning programs of the vehicle.
+    pub fn preconditioning_programs(programs: Vec<PreconditioningProgram>) -> RemoteRequest {
+        RemoteRequest {
+            label: Some("preconditioning_programs".to_owned()),
+            preconditioning: Some(RemotePreconditioning {
+                air_conditioning: RemoteAirConditioning { immediate: None, programs: Some(programs) },
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemotePreconditioning {
+    pub air_conditioning: RemoteAirConditioning,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteAirConditioning {
+    pub immediate: Option<bool>,
+    pub programs: Option<Vec<PreconditioningProgram>>,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteCharging {
+    pub immediate: Option<bool>,
+    #[serde(rename = "preferedSchedule")]
+    pub prefered_schedule: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteState {
+    pub state: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteLights {
+    pub on: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteResponse {
+    pub remote_action_id: Option<String>,
+    pub status: Option<String>,
+}
+
+/// Remote commands which can be triggered by name, e.g. from MQTT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RemoteAction {
+    ChargeNow,
+    StopCharging,
+    PreconditioningOn,
+    PreconditioningOff,
+    Lock,
+    Unlock,
+    Horn,
+    Lights,
+}
+
+impl RemoteAction {
+    pub const ALL: [RemoteAction; 8] = [
+        RemoteAction::ChargeNow,
+        RemoteAction::StopCharging,
+        RemoteAction::PreconditioningOn,
+        RemoteAction::PreconditioningOff,
+        RemoteAction::Lock,
+        RemoteAction::Unlock,
+        RemoteAction::Horn,
+        RemoteAction::Lights,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RemoteAction::ChargeNow => "charge_now",
+            RemoteAction::StopCharging => "stop_charging",
+            RemoteAction::PreconditioningOn => "preconditioning_on",
+            RemoteAction::PreconditioningOff => "preconditioning_off",
+            RemoteAction::Lock => "lock",
+            RemoteAction::Unlock => "unlock",
+            RemoteAction::Horn => "horn",
+            RemoteAction::Lights => "lights",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RemoteAction> {
+        RemoteAction::ALL.into_iter().find(|a| a.name().eq(name))
+    }
+
+    pub fn to_request(self) -> RemoteRequest {
+        let mut req = RemoteRequest { label: Some(self.name().to_owned()), ..Default::default() };
+        match self {
+            RemoteAction::ChargeNow | RemoteAction::StopCharging => {
+                req.charging = Some(RemoteCharging {
+                    immediate: Some(self == RemoteAction::ChargeNow),
+                    prefered_schedule: None,
+                });
+            },
+            RemoteAction::PreconditioningOn | RemoteAction::PreconditioningOff => {
+                req.preconditioning = Some(RemotePreconditioning {
+                    air_conditioning: RemoteAirConditioning { immediate: Some(self == RemoteAction::PreconditioningOn), programs: None },
+                });
+            },
+            RemoteAction::Lock => req.door = Some(RemoteState { state: "Locked".to_owned() }),
+            RemoteAction::Unlock => req.door = Some(RemoteState { state: "Unlocked".to_owned() }),
+            RemoteAction::Horn => req.horn = Some(RemoteState { state: "Activated".to_owned() }),
+            RemoteAction::Lights => req.lights = Some(RemoteLights { on: true }),
+        }
+        req
+    }
+}
+
+/// When the vehicle starts charging after it is plugged in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChargingSchedule {
+    Immediate,
+    // local time of day, whole minutes only
+    Delayed(NaiveTime),
+}
+
+impl ChargingSchedule {
+    /// Immediate charging is the same request as `RemoteAction::ChargeNow`.
+    pub fn to_request(self) -> RemoteRequest {
+        match self {
+            ChargingSchedule::Immediate => RemoteAction::ChargeNow.to_request(),
+            ChargingSchedule::Delayed(start) => RemoteRequest {
+                label: Some("charging_schedule".to_owned()),
+                charging: Some(RemoteCharging { immediate: Some(false), prefered_schedule: Some(format_time_of_day(start)) }),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl FromStr for ChargingSchedule {
+    type Err = String;
+
+    /// Parses `now`, a local time like `22:30` or a duration like `PT22H30M`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq("now") || s.eq("immediate") {
+            return Ok(ChargingSchedule::Immediate);
+        }
+        let start = parse_time_of_day(s)
+            .or_else(|| NaiveTime::parse_from_str(s, "%H:%M").ok())
+            .ok_or_else(|| format!("invalid charging start '{}', expected now, HH:MM or PT<h>H<m>M", s))?;
+        Ok(ChargingSchedule::Delayed(start))
+    }
+}
+
+impl fmt::Display for ChargingSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChargingSchedule::Immediate => write!(f, "immediate"),
+            ChargingSchedule::Delayed(start) => write!(f, "delayed to {}", start.format("%H:%M")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, start};
+
+    #[test]
+    fn parses_charging_schedules() {
+        let delayed = ChargingSchedule::Delayed(NaiveTime::from_hms_opt(22, 30, 0).unwrap());
+        assert_eq!("now".parse(), Ok(ChargingSchedule::Immediate));
+        assert_eq!("22:30".parse(), Ok(delayed));
+        assert_eq!("PT22H30M".parse(), Ok(delayed));
+        assert_eq!("PT0H0M".parse(), Ok(ChargingSchedule::Delayed(NaiveTime::MIN)));
+        assert_eq!("PT45M".parse(), Ok(ChargingSchedule::Delayed(NaiveTime::from_hms_opt(0, 45, 0).unwrap())));
+        for invalid in ["22:30:15", "tonight", "PT", "PT24H", "PT23H60M", "PT90M", "PT0S", "PTH30M", "PT22H30"] {
+            assert!(invalid.parse::<ChargingSchedule>().is_err(), "{} accepted", invalid);
+        }
+
+        let request = serde_json::to_value(delayed.to_request()).unwrap();
+        assert_eq!(request["charging"]["immediate"], false);
+        assert_eq!(request["charging"]["preferedSchedule"], "PT22H30M");
+        assert_eq!(serde_json::to_value(ChargingSchedule::Immediate.to_request()).unwrap(),
+            serde_json::to_value(RemoteAction::ChargeNow.to_request()).unwrap());
+    }
+
+    #[test]
+    fn reads_schedule_from_status() {
+        let mut status = testutil::status(start());
+        let charging = &mut status.energy[0].extension.as_mut().unwrap().electric.charging;
+        assert_eq!(charging.schedule(), ChargingSchedule::Delayed(NaiveTime::from_hms_opt(22, 30, 0).unwrap()));
+        charging.next_delayed_time = "PT0S".to_owned();
+        assert_eq!(charging.schedule(), ChargingSchedule::Immediate);
+    }
+}
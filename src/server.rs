@@ -0,0 +1,284 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::HashMap, error::Error, time::{Duration, Instant}};
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::history::HistoryDb;
+use crate::psa::api::ApiClient;
+use crate::psa::model::{ApiConfig, RemoteAction, VehicleStatus, VehiclesList, VehiclesListElement};
+use crate::trips;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServeConfig {
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    // keys accepted in the X-Api-Key header
+    pub api_keys: Vec<String>,
+    // seconds a fetched status is served from cache
+    #[serde(default = "default_cache_seconds")]
+    pub cache_seconds: u64,
+}
+
+fn default_listen() -> String {
+    "127.0.0.1:8080".to_owned()
+}
+
+fn default_cache_seconds() -> u64 {
+    60
+}
+
+struct Account<'a> {
+    profile: String,
+    client: ApiClient<'a>,
+    cars: VehiclesList,
+}
+
+/// Local HTTP API on top of the API clients of the configured profiles.
+pub struct ApiServer<'a> {
+    config: ServeConfig,
+    accounts: Vec<Account<'a>>,
+    cache: HashMap<String, (Instant, VehicleStatus)>,
+    db: HistoryDb,
+}
+
+type HandlerResult = Result<(u16, Value), Box<dyn Error>>;
+
+fn not_found() -> HandlerResult {
+    Ok((404, json!({ "error": "not found" })))
+}
+
+fn bad_request(message: String) -> HandlerResult {
+    Ok((400, json!({ "error": message })))
+}
+
+fn parse_time(params: &HashMap<String, String>, name: &str, default: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    match params.get(name) {
+        Some(value) => value.parse().map_err(|e| format!("invalid {} {}: {}", name, value, e)),
+        None => Ok(default),
+    }
+}
+
+/// `from` and `to` query parameters, all history up to now by default.
+fn time_range(params: &HashMap<String, String>) -> Result<(DateTime<Utc>, DateTime<Utc>), String> {
+    Ok((parse_time(params, "from", DateTime::<Utc>::MIN_UTC)?, parse_time(params, "to", Utc::now())?))
+}
+
+/// Compares the key in constant time, digests of both so the length of the
+/// configured key is not revealed either.
+fn key_matches(key: &str, given: &str) -> bool {
+    openssl::memcmp::eq(&openssl::sha::sha256(key.as_bytes()), &openssl::sha::sha256(given.as_bytes()))
+}
+
+impl<'a> ApiServer<'a> {
+    pub fn new(config: &ServeConfig, db: HistoryDb) -> Result<ApiServer<'a>, Box<dyn Error>> {
+        if config.api_keys.is_empty() {
+            return Err("No api_keys configured in the serve section".into());
+        }
+        Ok(ApiServer {
+            config: config.clone(),
+            accounts: vec![],
+            cache: HashMap::new(),
+            db,
+        })
+    }
+
+    pub fn add_account(&mut self, profile: &str, client: ApiClient<'a>, cars: VehiclesList) {
+        self.accounts.push(Account { profile: profile.to_owned(), client, cars });
+    }
+
+    fn api_configs(&self) -> Vec<ApiConfig> {
+        self.accounts.iter().map(|a| a.client.config()).collect()
+    }
+
+    /// Serves requests one after another, `after_change` is called after
+    /// requests which changed the API configs, e.g. to persist refreshed
+    /// tokens.
+    pub fn run<F>(&mut self, mut after_change: F) -> Result<(), Box<dyn Error>> where F: FnMut() -> Result<(), Box<dyn Error>> {
+        let server = Server::http(&self.config.listen).map_err(|e| e.to_string())?;
+        println!("Listening on http://{}", self.config.listen);
+
+        for request in server.incoming_requests() {
+            let before = self.api_configs();
+            let api_key = request.headers().iter().find(|h| h.field.equiv("X-Api-Key")).map(|h| h.value.as_str());
+            let (code, body) = self.respond(request.method(), request.url(), api_key);
+
+            let res = Response::from_string(body.to_string())
+                .with_status_code(code)
+                .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+            if let Err(e) = request.respond(res) {
+                eprintln!("response failed: {}", e);
+            }
+            if self.api_configs() != before {
+                after_change()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&mut self, method: &Method, url: &str, api_key: Option<&str>) -> (u16, Value) {
+        if !self.authorized(api_key) {
+            return (401, json!({ "error": "invalid api key" }));
+        }
+        self.handle(method, url).unwrap_or_else(|e| (500, json!({ "error": e.to_string() })))
+    }
+
+    fn authorized(&self, api_key: Option<&str>) -> bool {
+        api_key.is_some_and(|given| self.config.api_keys.iter().fold(false, |found, k| key_matches(k, given) | found))
+    }
+
+    fn serves(&self, vin: &str) -> bool {
+        self.accounts.iter().any(|a| a.cars.vehicles.iter().any(|c| c.vin.eq(vin)))
+    }
+
+    fn find(&mut self, vin: &str) -> Option<(&mut ApiClient<'a>, VehiclesListElement)> {
+        self.accounts.iter_mut().find_map(|a| {
+            let car = a.cars.vehicles.iter().find(|c| c.vin.eq(vin))?.clone();
+            Some((&mut a.client, car))
+        })
+    }
+
+    fn status(&mut self, vin: &str) -> HandlerResult {
+        if let Some((fetched, status)) = self.cache.get(vin) {
+            if fetched.elapsed() < Duration::from_secs(self.config.cache_seconds) {
+                return Ok((200, serde_json::to_value(status)?));
+            }
+        }
+        let (client, car) = match self.find(vin) {
+            Some(found) => found,
+            None => return not_found(),
+        };
+        let status = client.connectedcar_get_vehicle_status(&car.id)?;
+        self.db.insert(vin, &status)?;
+        let body = serde_json::to_value(&status)?;
+        self.cache.insert(vin.to_owned(), (Instant::now(), status));
+        Ok((200, body))
+    }
+
+    fn handle(&mut self, method: &Method, url: &str) -> HandlerResult {
+        let url = reqwest::Url::parse(&format!("http://localhost{}", url))?;
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let segments: Vec<&str> = url.path_segments().map_or(vec![], |s| s.filter(|s| !s.is_empty()).collect());
+
+        match (method, segments.as_slice()) {
+            (Method::Get, ["vehicles"]) => {
+                let vehicles = self.accounts.iter()
+                    .flat_map(|a| a.cars.vehicles.iter().map(|c| json!({
+                        "vin": c.vin,
+                        "brand": c.brand,
+                        "id": c.id,
+                        "profile": a.profile,
+                    })))
+                    .collect::<Vec<_>>();
+                Ok((200, json!(vehicles)))
+            },
+            (Method::Get, ["vehicles", vin, "status"]) => self.status(vin),
+            // the database may hold vehicles of profiles not served
+            (Method::Get, ["vehicles", vin, "history" | "trips"]) if !self.serves(vin) => not_found(),
+            (Method::Get, ["vehicles", vin, "history"]) => {
+                let (from, to) = match time_range(&params) {
+                    Ok(range) => range,
+                    Err(e) => return bad_request(e),
+                };
+                Ok((200, serde_json::to_value(self.db.snapshots(vin, from, to)?)?))
+            },
+            (Method::Get, ["vehicles", vin, "trips"]) => {
+                let (from, to) = match time_range(&params) {
+                    Ok(range) => range,
+                    Err(e) => return bad_request(e),
+                };
+                Ok((200, serde_json::to_value(trips::load_trips(&self.db, vin, from, to)?)?))
+            },
+            (Method::Post, ["vehicles", vin, "actions", action]) => {
+                let action = match RemoteAction::from_name(action) {
+                    Some(action) => action,
+                    None => return bad_request(format!("unknown action {}", action)),
+                };
+                let (client, car) = match self.find(vin) {
+                    Some(found) => found,
+                    None => return not_found(),
+                };
+                let res = client.connectedcar_remote_action(&car.id, action)?;
+                // the next status request should show the result
+                self.cache.remove(*vin);
+                Ok((202, serde_json::to_value(res)?))
+            },
+            _ => not_found(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, api_config, car, http_server, start, VIN};
+    use std::cell::RefCell;
+
+    fn config() -> ServeConfig {
+        ServeConfig { listen: default_listen(), api_keys: vec!["first".to_owned(), "second".to_owned()], cache_seconds: 60 }
+    }
+
+    fn server(api: &RefCell<ApiConfig>) -> ApiServer<'_> {
+        let mut server = ApiServer::new(&config(), HistoryDb::open(":memory:").unwrap()).unwrap();
+        server.add_account("default", ApiClient::new(api), VehiclesList { vehicles: vec![car()] });
+        server
+    }
+
+    #[test]
+    fn rejects_missing_and_wrong_keys() {
+        let api = api_config("http://127.0.0.1:1");
+        let mut server = server(&api);
+        assert_eq!(server.respond(&Method::Get, "/vehicles", None).0, 401);
+        assert_eq!(server.respond(&Method::Get, "/vehicles", Some("firs")).0, 401);
+        assert_eq!(server.respond(&Method::Get, "/vehicles", Some("first2")).0, 401);
+        let (code, body) = server.respond(&Method::Get, "/vehicles", Some("second"));
+        assert_eq!(code, 200);
+        assert_eq!(body[0]["vin"], VIN);
+        assert_eq!(body[0]["profile"], "default");
+    }
+
+    #[test]
+    fn rejects_invalid_time_range() {
+        let api = api_config("http://127.0.0.1:1");
+        let mut server = server(&api);
+        let url = format!("/vehicles/{}/history?from=yesterday", VIN);
+        let (code, body) = server.respond(&Method::Get, &url, Some("first"));
+        assert_eq!(code, 400);
+        assert!(body["error"].as_str().unwrap().starts_with("invalid from yesterday"));
+        assert_eq!(server.respond(&Method::Get, &format!("/vehicles/{}/trips?to=1", VIN), Some("first")).0, 400);
+    }
+
+    #[test]
+    fn serves_history_of_own_vehicles_only() {
+        let api = api_config("http://127.0.0.1:1");
+        let mut server = server(&api);
+        server.db.insert(VIN, &testutil::status(start())).unwrap();
+        server.db.insert("OTHERVIN", &testutil::status(start())).unwrap();
+
+        let (code, body) = server.respond(&Method::Get, &format!("/vehicles/{}/history?from=2024-03-01T00:00:00Z", VIN), Some("first"));
+        assert_eq!(code, 200);
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(server.respond(&Method::Get, "/vehicles/OTHERVIN/history", Some("first")).0, 404);
+        assert_eq!(server.respond(&Method::Get, "/vehicles/OTHERVIN/trips", Some("first")).0, 404);
+    }
+
+    #[test]
+    fn caches_fetched_status() {
+        let status = serde_json::to_string(&testutil::status(start())).unwrap();
+        let (url, requests) = http_server(move |_| (200, status.clone()));
+        let api = api_config(&url);
+        let mut server = server(&api);
+        let before = server.api_configs();
+
+        for _ in 0..2 {
+            let (code, body) = server.respond(&Method::Get, &format!("/vehicles/{}/status", VIN), Some("first"));
+            assert_eq!(code, 200);
+            assert_eq!(body["odometer"]["mileage"].as_f64().map(|m| m.round()), Some(12346.0));
+        }
+        assert!(requests.recv().unwrap().path.starts_with("/connectedcar/v4/user/vehicles/car-id/status"));
+        assert!(requests.try_recv().is_err());
+        assert_eq!(server.db.latest(VIN).unwrap().unwrap().updated_at, start());
+        // a valid token is not refreshed, nothing to persist
+        assert!(server.api_configs() == before);
+    }
+}
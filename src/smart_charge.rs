@@ -0,0 +1,341 @@
+use chrono::{DateTime, Duration, Local, NaiveTime, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, error::Error, fs::File};
+
+use crate::history::Snapshot;
+use crate::psa::api::ApiClient;
+use crate::psa::model::{ChargingSchedule, RemoteAction, VehicleStatus, VehiclesListElement};
+use crate::watch::WatchHandler;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceSource {
+    File(String),
+    Url(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmartChargeConfig {
+    // JSON list of hourly prices, e.g. [{"start": "2024-01-01T00:00:00Z", "price": 0.21}]
+    pub prices: PriceSource,
+    // charging power if it can not be derived from the charging rate
+    #[serde(default = "default_power")]
+    pub power_kw: f64,
+    // goals by VIN
+    #[serde(default)]
+    pub vehicles: BTreeMap<String, ChargeGoal>,
+}
+
+fn default_power() -> f64 {
+    7.4
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChargeGoal {
+    // local time the vehicle is needed
+    pub departure: NaiveTime,
+    // SoC in percent at departure
+    pub target: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceSlot {
+    pub start: DateTime<Utc>,
+    pub price: f64,
+}
+
+impl PriceSlot {
+    pub fn end(&self) -> DateTime<Utc> {
+        self.start + Duration::hours(1)
+    }
+}
+
+pub fn load_prices(source: &PriceSource) -> Result<Vec<PriceSlot>, Box<dyn Error>> {
+    let mut prices: Vec<PriceSlot> = match source {
+        PriceSource::File(path) => serde_json::from_reader(File::open(path)?)?,
+        PriceSource::Url(url) => reqwest::blocking::get(url)?.error_for_status()?.json()?,
+    };
+    prices.sort_by_key(|p| p.start);
+    Ok(prices)
+}
+
+/// Next occurrence of the local departure time after now.
+pub fn next_departure(now: DateTime<Utc>, departure: NaiveTime) -> DateTime<Utc> {
+    let local = now.with_timezone(&Local);
+    let today = local.date_naive().and_time(departure);
+    let at = Local.from_local_datetime(&today).earliest().unwrap_or(local);
+    let at = if at <= local { at + Duration::days(1) } else { at };
+    at.with_timezone(&Utc)
+}
+
+/// Charging power in kW, the charging rate is reported in km of range per
+/// hour and converted with the consumption derived from SoC and range.
+pub fn charging_power_kw(snapshot: &Snapshot, fallback: f64) -> f64 {
+    let consumption = match (snapshot.battery_capacity_kwh(), snapshot.soc, snapshot.autonomy) {
+        (Some(capacity), Some(soc), Some(autonomy)) if autonomy > 0 => capacity * soc as f64 / 100.0 / autonomy as f64,
+        _ => return fallback,
+    };
+    match snapshot.charging_rate {
+        Some(rate) if rate > 0 => rate as f64 * consumption,
+        _ => fallback,
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedSlot {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub price: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChargePlan {
+    pub slots: Vec<PlannedSlot>,
+    pub energy_kwh: f64,
+    pub cost: f64,
+    // energy the known prices do not cover until departure
+    pub missing_kwh: f64,
+}
+
+impl ChargePlan {
+    pub fn active_at(&self, time: DateTime<Utc>) -> bool {
+        self.slots.iter().any(|s| s.start <= time && time < s.end)
+    }
+
+    pub fn next_start(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.slots.iter().map(|s| s.start).find(|s| *s > time)
+    }
+}
+
+/// Picks the cheapest hours between now and departure to charge the energy,
+/// the slot in progress only counts with its remaining time.
+pub fn plan(prices: &[PriceSlot], now: DateTime<Utc>, departure: DateTime<Utc>, energy_kwh: f64, power_kw: f64) -> ChargePlan {
+    let mut candidates = prices.iter()
+        .filter(|p| p.end() > now && p.start < departure)
+        .map(|p| PlannedSlot { start: p.start.max(now), end: p.end().min(departure), price: p.price })
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| a.price.total_cmp(&b.price).then(a.start.cmp(&b.start)));
+
+    let mut remaining = energy_kwh.max(0.0);
+    let mut cost = 0.0;
+    let mut slots = Vec::new();
+    for mut slot in candidates {
+        if remaining <= 0.0 || power_kw <= 0.0 {
+            break;
+        }
+        let hours = (slot.end - slot.start).num_seconds() as f64 / 3600.0;
+        let used = hours.min(remaining / power_kw);
+        slot.end = slot.start + Duration::seconds((used * 3600.0).ceil() as i64);
+        remaining -= used * power_kw;
+        cost += used * power_kw * slot.price;
+        slots.push(slot);
+    }
+    slots.sort_by_key(|s| s.start);
+
+    ChargePlan {
+        slots,
+        energy_kwh: energy_kwh.max(0.0) - remaining.max(0.0),
+        cost,
+        missing_kwh: remaining.max(0.0),
+    }
+}
+
+/// Computes the plan for the goal from the current state of the vehicle.
+pub fn plan_for(config: &SmartChargeConfig, prices: &[PriceSlot], goal: &ChargeGoal, snapshot: &Snapshot, now: DateTime<Utc>) -> Result<ChargePlan, Box<dyn Error>> {
+    let capacity = snapshot.battery_capacity_kwh().ok_or("Battery capacity not reported")?;
+    let soc = snapshot.soc.ok_or("SoC not reported")?;
+    let energy = goal.target.saturating_sub(soc) as f64 / 100.0 * capacity;
+    let power = charging_power_kw(snapshot, config.power_kw);
+    Ok(plan(prices, now, next_departure(now, goal.departure), energy, power))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Sent {
+    Immediate,
+    Delayed(DateTime<Utc>),
+    Stop,
+}
+
+/// Starts charging in the planned slots, outside of them charging is stopped
+/// and delayed to the next slot. The plan is updated on every status.
+pub struct SmartCharger {
+    config: SmartChargeConfig,
+    dry_run: bool,
+    prices: Vec<PriceSlot>,
+    prices_loaded: Option<DateTime<Utc>>,
+    sent: BTreeMap<String, Sent>,
+}
+
+impl SmartCharger {
+    pub fn new(config: &SmartChargeConfig, dry_run: bool) -> SmartCharger {
+        SmartCharger {
+            config: config.clone(),
+            dry_run,
+            prices: Vec::new(),
+            prices_loaded: None,
+            sent: BTreeMap::new(),
+        }
+    }
+
+    fn refresh_prices(&mut self, now: DateTime<Utc>) -> Result<(), Box<dyn Error>> {
+        // None orders before any time
+        if self.prices_loaded <= Some(now - Duration::hours(1)) {
+            self.prices = load_prices(&self.config.prices)?;
+            self.prices_loaded = Some(now);
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, client: &mut ApiClient, car: &VehiclesListElement, command: Sent) -> Result<(), Box<dyn Error>> {
+        if self.sent.get(&car.vin) == Some(&command) {
+            return Ok(());
+        }
+        let request = match command {
+            Sent::Immediate => RemoteAction::ChargeNow.to_request(),
+            Sent::Stop => RemoteAction::StopCharging.to_request(),
+            Sent::Delayed(start) => ChargingSchedule::Delayed(start.with_timezone(&Local).time().with_second(0).unwrap_or_default()).to_request(),
+        };
+        let prefix = if self.dry_run { "would send" } else { "sending" };
+        println!("{} smart charging {} {:?}", car.vin, prefix, command);
+        if !self.dry_run {
+            client.connectedcar_send_remote(&car.id, &request)?;
+        }
+        self.sent.insert(car.vin.to_owned(), command);
+        Ok(())
+    }
+}
+
+impl WatchHandler for SmartCharger {
+    fn on_status(&mut self, client: &mut ApiClient, car: &VehiclesListElement, status: &VehicleStatus) -> Result<(), Box<dyn Error>> {
+        let goal = match self.config.vehicles.get(&car.vin) {
+            Some(goal) => goal.clone(),
+            None => return Ok(()),
+        };
+        if !status.is_plugged() {
+            self.sent.remove(&car.vin);
+            return Ok(());
+        }
+        let snapshot = Snapshot::from_status(&car.vin, status)?;
+        let charging = status.is_charging();
+        if snapshot.soc.is_some_and(|soc| soc >= goal.target) {
+            if charging {
+                self.send(client, car, Sent::Stop)?;
+            }
+            return Ok(());
+        }
+
+        let now = Utc::now();
+        self.refresh_prices(now)?;
+        let plan = plan_for(&self.config, &self.prices, &goal, &snapshot, now)?;
+        if plan.active_at(now) {
+            if !charging {
+                self.send(client, car, Sent::Immediate)?;
+            }
+        } else if charging {
+            // charging outside the plan, e.g. the delay was not applied or
+            // charging was started at the car, delayed again once stopped
+            self.send(client, car, Sent::Stop)?;
+        } else if let Some(next) = plan.next_start(now) {
+            self.send(client, car, Sent::Delayed(next))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, car, http_server, start, VIN};
+    use serde_json::json;
+
+    fn slot(hours: i64, price: f64) -> PriceSlot {
+        PriceSlot { start: start() + Duration::hours(hours), price }
+    }
+
+    #[test]
+    fn plans_cheapest_slots_before_departure() {
+        let prices = vec![slot(0, 0.3), slot(1, 0.1), slot(2, 0.2), slot(3, 0.05), slot(4, 0.01)];
+        let planned = plan(&prices, start() + Duration::minutes(30), start() + Duration::hours(4), 10.0, 5.0);
+        assert_eq!(planned.slots.iter().map(|s| s.start).collect::<Vec<_>>(), vec![start() + Duration::hours(1), start() + Duration::hours(3)]);
+        assert!((planned.cost - 0.75).abs() < 1e-9);
+        assert_eq!(planned.missing_kwh, 0.0);
+        assert!(!planned.active_at(start() + Duration::minutes(30)));
+        assert_eq!(planned.next_start(start() + Duration::minutes(30)), Some(start() + Duration::hours(1)));
+
+        let short = plan(&prices, start(), start() + Duration::hours(1), 10.0, 5.0);
+        assert_eq!(short.energy_kwh, 5.0);
+        assert_eq!(short.missing_kwh, 5.0);
+        assert!(short.active_at(start()));
+    }
+
+    fn status(soc: u32, charging: bool) -> VehicleStatus {
+        let mut status = testutil::status(Utc::now());
+        status.energy[0].level = soc;
+        status.energy[0].extension.as_mut().unwrap().electric.charging.status =
+            if charging { "InProgress" } else { "Stopped" }.to_owned();
+        status
+    }
+
+    /// Charger with hourly prices from now on, the hours at `cheap` offsets
+    /// are cheap, and the remote requests it sent.
+    fn charger(cheap: &'static [i64]) -> (SmartCharger, String, std::sync::mpsc::Receiver<testutil::HttpRequest>) {
+        let hour = Utc::now().with_minute(0).unwrap().with_second(0).unwrap().with_nanosecond(0).unwrap();
+        let prices = (0..24).map(|h| json!({
+            "start": hour + Duration::hours(h),
+            "price": if cheap.contains(&h) { 0.1 } else { 0.5 },
+        })).collect::<Vec<_>>();
+        let (url, requests) = http_server(move |r| match r.path.as_str() {
+            "/prices" => (200, json!(prices).to_string()),
+            _ => (200, json!({ "remoteActionId": "1" }).to_string()),
+        });
+        let config = SmartChargeConfig {
+            prices: PriceSource::Url(format!("{}/prices", url)),
+            power_kw: default_power(),
+            vehicles: BTreeMap::from([(VIN.to_owned(), ChargeGoal {
+                departure: (Local::now() + Duration::hours(12)).time(),
+                target: 80,
+            })]),
+        };
+        (SmartCharger::new(&config, false), url, requests)
+    }
+
+    fn remotes(requests: &std::sync::mpsc::Receiver<testutil::HttpRequest>) -> usize {
+        requests.try_iter().filter(|r| r.path.contains("/remotes")).count()
+    }
+
+    #[test]
+    fn stops_charging_outside_slot_and_delays_to_next() {
+        let (mut charger, url, requests) = charger(&[3, 4]);
+        let api = testutil::api_config(&url);
+        let mut client = ApiClient::new(&api);
+
+        charger.on_status(&mut client, &car(), &status(50, true)).unwrap();
+        assert_eq!(charger.sent[VIN], Sent::Stop);
+        assert_eq!(remotes(&requests), 1);
+
+        charger.on_status(&mut client, &car(), &status(50, false)).unwrap();
+        assert!(matches!(charger.sent[VIN], Sent::Delayed(next) if next > Utc::now() + Duration::hours(2)));
+        assert_eq!(remotes(&requests), 1);
+
+        // the delay is not sent again, charging started at the car is stopped again
+        charger.on_status(&mut client, &car(), &status(50, false)).unwrap();
+        assert_eq!(remotes(&requests), 0);
+        charger.on_status(&mut client, &car(), &status(51, true)).unwrap();
+        assert_eq!(charger.sent[VIN], Sent::Stop);
+        assert_eq!(remotes(&requests), 1);
+    }
+
+    #[test]
+    fn charges_in_slot_and_stops_at_target() {
+        let (mut charger, url, requests) = charger(&[0, 1, 2]);
+        let api = testutil::api_config(&url);
+        let mut client = ApiClient::new(&api);
+
+        charger.on_status(&mut client, &car(), &status(50, false)).unwrap();
+        assert_eq!(charger.sent[VIN], Sent::Immediate);
+        charger.on_status(&mut client, &car(), &status(60, true)).unwrap();
+        charger.on_status(&mut client, &car(), &status(80, true)).unwrap();
+        assert_eq!(charger.sent[VIN], Sent::Stop);
+        assert_eq!(remotes(&requests), 2);
+    }
+}
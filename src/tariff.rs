@@ -0,0 +1,331 @@
+use chrono::{Datelike, Duration, Local, NaiveTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, error::Error, io::Write};
+
+use crate::charging::ChargingSession;
+use crate::geo;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Tariff {
+    Flat { price: f64 },
+    // windows are in local time, the end may be before the start to span midnight
+    TimeOfUse { default_price: f64, windows: Vec<TariffWindow> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TariffWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub price: f64,
+}
+
+impl TariffWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl Tariff {
+    /// Price per kWh at the given local time.
+    pub fn price_at(&self, time: NaiveTime) -> f64 {
+        match self {
+            Tariff::Flat { price } => *price,
+            Tariff::TimeOfUse { default_price, windows } => windows.iter()
+                .find(|w| w.contains(time))
+                .map_or(*default_price, |w| w.price),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocationTariff {
+    pub name: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default = "default_radius")]
+    pub radius_m: f64,
+    pub tariff: Tariff,
+}
+
+fn default_radius() -> f64 {
+    200.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TariffConfig {
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    pub default: Tariff,
+    #[serde(default)]
+    pub locations: Vec<LocationTariff>,
+}
+
+fn default_currency() -> String {
+    "EUR".to_owned()
+}
+
+const DEFAULT_LOCATION: &str = "other";
+
+#[derive(Debug, Clone)]
+pub struct SessionCost {
+    pub session: ChargingSession,
+    pub location: String,
+    pub energy_kwh: f64,
+    pub cost: f64,
+    // time of use prices applied to a session missed by the polling
+    pub estimated: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MonthlyCost {
+    pub sessions: u32,
+    pub estimated: u32,
+    pub energy_kwh: f64,
+    pub cost: f64,
+}
+
+impl TariffConfig {
+    fn tariff_for(&self, session: &ChargingSession) -> (&str, &Tariff) {
+        if let Some(position) = session.position {
+            if let Some(l) = self.locations.iter().find(|l| geo::distance_m(position, (l.latitude, l.longitude)) <= l.radius_m) {
+                return (&l.name, &l.tariff);
+            }
+        }
+        (DEFAULT_LOCATION, &self.default)
+    }
+
+    /// Cost of the session, the energy is spread evenly over the session
+    /// duration to apply time of use prices. `None` if the energy is unknown.
+    /// The cost of a session missed by the polling is only an estimate with
+    /// time of use prices, the car charged at some time within the gap.
+    pub fn session_cost(&self, session: &ChargingSession) -> Option<SessionCost> {
+        self.session_cost_in(session, &Local)
+    }
+
+    fn session_cost_in<Tz: TimeZone>(&self, session: &ChargingSession, tz: &Tz) -> Option<SessionCost> {
+        let energy_kwh = session.energy_kwh()?;
+        let (location, tariff) = self.tariff_for(session);
+
+        let minutes = session.duration().num_minutes().max(1);
+        let energy_per_minute = energy_kwh / minutes as f64;
+        let start = session.start_time.with_timezone(tz);
+        let cost = (0..minutes)
+            .map(|m| tariff.price_at((start.clone() + Duration::minutes(m)).time()) * energy_per_minute)
+            .sum();
+
+        Some(SessionCost {
+            session: session.clone(),
+            location: location.to_owned(),
+            energy_kwh,
+            cost,
+            estimated: session.estimated && matches!(tariff, Tariff::TimeOfUse { .. }),
+        })
+    }
+}
+
+/// Sums the session costs by month (`YYYY-MM`, local time) and location.
+pub fn monthly(costs: &[SessionCost]) -> BTreeMap<(String, String), MonthlyCost> {
+    monthly_in(costs, &Local)
+}
+
+fn monthly_in<Tz: TimeZone>(costs: &[SessionCost], tz: &Tz) -> BTreeMap<(String, String), MonthlyCost> {
+    let mut months: BTreeMap<(String, String), MonthlyCost> = BTreeMap::new();
+    for c in costs {
+        let start = c.session.start_time.with_timezone(tz);
+        let month = format!("{:04}-{:02}", start.year(), start.month());
+        let entry = months.entry((month, c.location.to_owned())).or_default();
+        entry.sessions += 1;
+        entry.estimated += c.estimated as u32;
+        entry.energy_kwh += c.energy_kwh;
+        entry.cost += c.cost;
+    }
+    months
+}
+
+pub fn write_sessions_csv<W: Write>(w: W, costs: &[SessionCost], currency: &str) -> Result<(), Box<dyn Error>> {
+    let mut csv = csv::Writer::from_writer(w);
+    csv.write_record(["vin", "start", "end", "location", "mode", "start_soc", "end_soc", "energy_kwh", &format!("cost_{}", currency), "estimated"])?;
+    for c in costs {
+        csv.write_record([
+            c.session.vin.to_owned(),
+            c.session.start_time.to_rfc3339(),
+            c.session.end_time.to_rfc3339(),
+            c.location.to_owned(),
+            c.session.mode.clone().unwrap_or_default(),
+            c.session.start_soc.to_string(),
+            c.session.end_soc.to_string(),
+            format!("{:.2}", c.energy_kwh),
+            format!("{:.2}", c.cost),
+            c.estimated.to_string(),
+        ])?;
+    }
+    csv.flush()?;
+    Ok(())
+}
+
+pub fn write_monthly_csv<W: Write>(w: W, months: &BTreeMap<(String, String), MonthlyCost>, currency: &str) -> Result<(), Box<dyn Error>> {
+    let mut csv = csv::Writer::from_writer(w);
+    csv.write_record(["month", "location", "sessions", "energy_kwh", &format!("cost_{}", currency), "estimated_sessions"])?;
+    for ((month, location), m) in months {
+        csv.write_record([
+            month.to_owned(),
+            location.to_owned(),
+            m.sessions.to_string(),
+            format!("{:.2}", m.energy_kwh),
+            format!("{:.2}", m.cost),
+            m.estimated.to_string(),
+        ])?;
+    }
+    csv.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap()
+    }
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    // 40 kWh battery, so every 10% are 4 kWh
+    fn session(start: DateTime<Utc>, hours: i64, start_soc: u32, end_soc: u32) -> ChargingSession {
+        ChargingSession {
+            id: None,
+            vin: "VIN".to_owned(),
+            start_time: start,
+            end_time: start + Duration::hours(hours),
+            start_soc,
+            end_soc,
+            capacity_kwh: Some(40.0),
+            position: Some((48.137, 11.575)),
+            mode: Some("Slow".to_owned()),
+            estimated: false,
+        }
+    }
+
+    fn night() -> TariffWindow {
+        TariffWindow { start: time(22, 0), end: time(6, 0), price: 0.2 }
+    }
+
+    fn config() -> TariffConfig {
+        TariffConfig {
+            currency: default_currency(),
+            default: Tariff::Flat { price: 0.5 },
+            locations: vec![LocationTariff {
+                name: "home".to_owned(),
+                latitude: 48.137,
+                longitude: 11.575,
+                radius_m: default_radius(),
+                tariff: Tariff::TimeOfUse { default_price: 0.4, windows: vec![night()] },
+            }],
+        }
+    }
+
+    fn cost(config: &TariffConfig, session: &ChargingSession) -> f64 {
+        let cost = config.session_cost_in(session, &Utc).unwrap().cost;
+        (cost * 1000.0).round() / 1000.0
+    }
+
+    #[test]
+    fn window_spans_midnight() {
+        let window = night();
+        for (hour, minute, inside) in [(21, 59, false), (22, 0, true), (23, 30, true), (0, 0, true), (5, 59, true), (6, 0, false), (12, 0, false)] {
+            assert_eq!(window.contains(time(hour, minute)), inside, "{}:{}", hour, minute);
+        }
+        let day = TariffWindow { start: time(9, 0), end: time(17, 0), price: 0.3 };
+        assert!(day.contains(time(9, 0)) && day.contains(time(16, 59)));
+        assert!(!day.contains(time(17, 0)) && !day.contains(time(8, 59)));
+    }
+
+    #[test]
+    fn prices_by_window_or_default() {
+        let tariff = Tariff::TimeOfUse {
+            default_price: 0.4,
+            windows: vec![night(), TariffWindow { start: time(12, 0), end: time(14, 0), price: 0.1 }],
+        };
+        assert_eq!(tariff.price_at(time(23, 0)), 0.2);
+        assert_eq!(tariff.price_at(time(13, 0)), 0.1);
+        assert_eq!(tariff.price_at(time(8, 0)), 0.4);
+        assert_eq!(Tariff::Flat { price: 0.3 }.price_at(time(23, 0)), 0.3);
+    }
+
+    #[test]
+    fn costs_session_at_flat_price_away_from_locations() {
+        let mut away = session(at(1, 12), 2, 40, 70);
+        away.position = Some((52.52, 13.405));
+        let flat = config().session_cost_in(&away, &Utc).unwrap();
+        assert_eq!(flat.location, "other");
+        assert!((flat.energy_kwh - 12.0).abs() < 1e-9);
+        // 12 kWh at 0.50
+        assert_eq!(cost(&config(), &away), 6.0);
+
+        away.position = None;
+        assert_eq!(config().session_cost_in(&away, &Utc).unwrap().location, "other");
+        away.capacity_kwh = None;
+        assert!(config().session_cost_in(&away, &Utc).is_none());
+    }
+
+    #[test]
+    fn splits_session_at_window_boundary() {
+        // 21:00 - 23:00, 12 kWh: 6 kWh at 0.40 and 6 kWh at 0.20
+        let boundary = session(at(1, 21), 2, 50, 80);
+        assert_eq!(config().session_cost_in(&boundary, &Utc).unwrap().location, "home");
+        assert_eq!(cost(&config(), &boundary), 3.6);
+        // 23:00 - 07:00, 16 kWh: 14 kWh within the window and 2 kWh after it
+        assert_eq!(cost(&config(), &session(at(1, 23), 8, 40, 80)), 3.6);
+        // 04:00 - 08:00, 8 kWh: half in the window, half at the default price
+        assert_eq!(cost(&config(), &session(at(2, 4), 4, 60, 80)), 2.4);
+    }
+
+    #[test]
+    fn flags_missed_sessions_at_time_of_use_prices() {
+        let mut missed = session(at(1, 18), 6, 40, 60);
+        missed.estimated = true;
+        assert!(config().session_cost_in(&missed, &Utc).unwrap().estimated);
+        missed.position = None;
+        // a flat price does not depend on when the car charged
+        assert!(!config().session_cost_in(&missed, &Utc).unwrap().estimated);
+        assert!(!config().session_cost_in(&session(at(1, 18), 6, 40, 60), &Utc).unwrap().estimated);
+    }
+
+    #[test]
+    fn sums_costs_by_month_and_location() {
+        let config = config();
+        let mut away = session(at(5, 12), 1, 70, 80);
+        away.position = None;
+        let mut missed = session(at(31, 23), 2, 40, 60);
+        missed.estimated = true;
+        let costs = [session(at(1, 23), 1, 50, 60), away, missed, session(at(2, 23), 1, 50, 70)].iter()
+            .map(|s| config.session_cost_in(s, &Utc).unwrap())
+            .collect::<Vec<_>>();
+
+        let months = monthly_in(&costs, &Utc);
+        assert_eq!(months.keys().cloned().collect::<Vec<_>>(), vec![
+            ("2024-03".to_owned(), "home".to_owned()),
+            ("2024-03".to_owned(), "other".to_owned()),
+        ]);
+        let home = &months[&("2024-03".to_owned(), "home".to_owned())];
+        assert_eq!((home.sessions, home.estimated), (3, 1));
+        assert!((home.energy_kwh - 20.0).abs() < 1e-9);
+        // 4 + 8 + 8 kWh at night
+        assert!((home.cost - 4.0).abs() < 1e-9);
+        let other = &months[&("2024-03".to_owned(), "other".to_owned())];
+        assert_eq!(other.sessions, 1);
+        assert!((other.cost - 2.0).abs() < 1e-9);
+
+        let april = session(Utc.with_ymd_and_hms(2024, 4, 1, 0, 30, 0).unwrap(), 1, 50, 60);
+        let months = monthly_in(&[config.session_cost_in(&april, &Utc).unwrap()], &Utc);
+        assert!(months.contains_key(&("2024-04".to_owned(), "home".to_owned())));
+    }
+}
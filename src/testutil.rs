@@ -0,0 +1,319 @@
+//! Fixtures and local stand-in servers for the unit tests.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde_json::json;
+use std::{cell::RefCell, collections::HashMap, io::{BufRead, BufReader, Read, Write}, net::{TcpListener, TcpStream}, sync::{mpsc::{self, Receiver}, Arc}, thread};
+
+use crate::history::Snapshot;
+use crate::psa::model::{ApiConfig, VehicleStatus, VehiclesListElement};
+
+pub const VIN: &str = "VR3UHZKXZLT123456";
+
+pub fn car() -> VehiclesListElement {
+    VehiclesListElement {
+        id: "car-id".to_owned(),
+        vin: VIN.to_owned(),
+        brand: "Peugeot".to_owned(),
+        pictures: vec![],
+        links: HashMap::new(),
+    }
+}
+
+/// Config with a valid token, requests go to `host`.
+pub fn api_config(host: &str) -> RefCell<ApiConfig> {
+    RefCell::new(ApiConfig {
+        host_api_prod: host.to_owned(),
+        access_token: "token".to_owned(),
+        token_expires: Some(Utc::now() + chrono::Duration::hours(1)),
+        remote_callback_id: "callback".to_owned(),
+        ..Default::default()
+    })
+}
+
+/// Parked, plugged in electric vehicle at 80% which is not charging.
+pub fn status(updated_at: DateTime<Utc>) -> VehicleStatus {
+    let at = updated_at.to_rfc3339();
+    let air_conditioning = json!({ "status": "Disabled", "createdAt": at, "updatedAt": at });
+    serde_json::from_value(json!({
+        "createdAt": at,
+        "updatedAt": at,
+        "lastPosition": {
+            "type": "Feature",
+            "geometry": { "type": "Point", "coordinates": [11.575, 48.137, 520.0] },
+            "properties": { "type": "Estimated", "createdAt": at },
+        },
+        "ignition": { "type": "Stop", "createdAt": at },
+        "battery": { "voltage": 12.6, "createdAt": at },
+        "privacy": { "state": "None", "createdAt": at },
+        "service": { "type": "Electric", "createdAt": at },
+        "environment": {
+            "luminosity": { "day": true, "createdAt": at },
+            "air": { "temp": 12.0, "createdAt": at },
+        },
+        "odometer": { "mileage": 12345.6, "createdAt": at },
+        "kinetic": { "moving": false, "createdAt": at },
+        "_links": {},
+        "preconditioning": { "airConditioning": air_conditioning },
+        "preconditionning": { "airConditioning": air_conditioning },
+        "energies": [],
+        "energy": [{
+            "createdAt": at,
+            "type": "Electric",
+            "level": 80,
+            "autonomy": 240,
+            "extension": {
+                "electric": {
+                    "battery": { "load": { "createdAt": at, "capacity": 46000, "residual": 36800 } },
+                    "charging": {
+                        "plugged": true,
+                        "status": "Stopped",
+                        "remainingTime": "PT0S",
+                        "chargingRate": 0,
+                        "chargingMode": "No",
+                        "nextDelayedTime": "PT22H30M",
+                    },
+                },
+            },
+        }],
+    })).unwrap()
+}
+
+/// Fixed start time of the synthetic histories.
+pub fn start() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 3, 1, 8, 0, 0).unwrap()
+}
+
+/// Parked, unplugged snapshot `minutes` after `start()`.
+pub fn snapshot(minutes: i64, mileage: f32, soc: u32) -> Snapshot {
+    Snapshot {
+        vin: VIN.to_owned(),
+        updated_at: start() + Duration::minutes(minutes),
+        soc: Some(soc),
+        autonomy: Some(soc * 3),
+        mileage,
+        battery_voltage: 12.6,
+        temperature: 12.0,
+        latitude: Some(48.137),
+        longitude: Some(11.575),
+        moving: false,
+        ignition: "Stop".to_owned(),
+        plugged: false,
+        charging_status: Some("Disconnected".to_owned()),
+        charging_rate: Some(0),
+        charging_mode: Some("No".to_owned()),
+        battery_capacity: Some(46000),
+        battery_residual: None,
+        privacy: "None".to_owned(),
+        raw: "{}".to_owned(),
+    }
+}
+
+/// Snapshot of the vehicle driving.
+pub fn driving(minutes: i64, mileage: f32, soc: u32) -> Snapshot {
+    Snapshot { moving: true, ignition: "Start".to_owned(), ..snapshot(minutes, mileage, soc) }
+}
+
+fn read_remaining_length(stream: &mut TcpStream) -> Option<usize> {
+    let (mut length, mut shift) = (0, 0);
+    loop {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).ok()?;
+        length |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Some(length);
+        }
+        shift += 7;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+/// MQTT 3.1.1 broker for a single client which acknowledges everything and
+/// reports the published messages.
+pub fn mqtt_broker() -> (u16, Receiver<MqttMessage>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        loop {
+            let mut header = [0u8];
+            if stream.read_exact(&mut header).is_err() {
+                return;
+            }
+            let length = match read_remaining_length(&mut stream) {
+                Some(length) => length,
+                None => return,
+            };
+            let mut body = vec![0u8; length];
+            if stream.read_exact(&mut body).is_err() {
+                return;
+            }
+            let reply: Vec<u8> = match header[0] >> 4 {
+                1 => vec![0x20, 0x02, 0x00, 0x00],
+                3 => {
+                    let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    let topic = String::from_utf8_lossy(&body[2..2 + topic_len]).to_string();
+                    let qos = (header[0] >> 1) & 0x03;
+                    let payload_start = 2 + topic_len + if qos > 0 { 2 } else { 0 };
+                    let message = MqttMessage {
+                        topic,
+                        payload: String::from_utf8_lossy(&body[payload_start..]).to_string(),
+                        retain: header[0] & 0x01 == 1,
+                    };
+                    if tx.send(message).is_err() {
+                        return;
+                    }
+                    if qos > 0 {
+                        vec![0x40, 0x02, body[2 + topic_len], body[3 + topic_len]]
+                    } else {
+                        vec![]
+                    }
+                },
+                8 => vec![0x90, 0x03, body[0], body[1], 0x01],
+                12 => vec![0xd0, 0x00],
+                _ => vec![],
+            };
+            if stream.write_all(&reply).is_err() {
+                return;
+            }
+        }
+    });
+    (port, rx)
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    // path with query
+    pub path: String,
+    // lower case names
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl HttpRequest {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+fn read_http_request(reader: &mut BufReader<TcpStream>) -> Option<HttpRequest> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok().filter(|n| *n > 0)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = (parts.next()?.to_owned(), parts.next()?.to_owned());
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok().filter(|n| *n > 0)?;
+        match line.trim_end().split_once(':') {
+            Some((name, value)) => headers.insert(name.to_lowercase(), value.trim().to_owned()),
+            None => break,
+        };
+    }
+    let length = headers.get("content-length").and_then(|l| l.parse().ok()).unwrap_or(0);
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(HttpRequest { method, path, headers, body: String::from_utf8_lossy(&body).to_string() })
+}
+
+/// HTTP/1.1 server answering each request with the status and JSON body
+/// returned by `respond`, reports the requests. Returns the base url.
+pub fn http_server<F>(respond: F) -> (String, Receiver<HttpRequest>)
+        where F: Fn(&HttpRequest) -> (u16, String) + Send + Sync + 'static {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    let respond = Arc::new(respond);
+    thread::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            let (tx, respond) = (tx.clone(), respond.clone());
+            thread::spawn(move || {
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                while let Some(request) = read_http_request(&mut reader) {
+                    let (status, body) = respond(&request);
+                    if tx.send(request).is_err() {
+                        return;
+                    }
+                    let response = format!("HTTP/1.1 {} Stand-in\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                        status, body.len(), body);
+                    if writer.write_all(response.as_bytes()).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (url, rx)
+}
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub from: String,
+    pub to: Vec<String>,
+    // headers and body as sent
+    pub data: String,
+}
+
+/// SMTP server without TLS and authentication which accepts every mail and
+/// reports it.
+pub fn smtp_server() -> (u16, Receiver<Mail>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut mail = Mail { from: "".to_owned(), to: vec![], data: "".to_owned() };
+                let mut in_data = false;
+                writer.write_all(b"220 localhost ESMTP stand-in\r\n").unwrap();
+                loop {
+                    let mut line = String::new();
+                    if !matches!(reader.read_line(&mut line), Ok(n) if n > 0) {
+                        return;
+                    }
+                    let reply = if in_data {
+                        if line != ".\r\n" {
+                            mail.data.push_str(&line);
+                            continue;
+                        }
+                        in_data = false;
+                        if tx.send(mail.clone()).is_err() {
+                            return;
+                        }
+                        "250 queued"
+                    } else {
+                        let upper = line.to_uppercase();
+                        let address = || line.split_once('<').and_then(|(_, a)| a.split_once('>')).map(|(a, _)| a.to_owned()).unwrap_or_default();
+                        if upper.starts_with("MAIL FROM") {
+                            mail = Mail { from: address(), to: vec![], data: "".to_owned() };
+                        } else if upper.starts_with("RCPT TO") {
+                            mail.to.push(address());
+                        } else if upper.starts_with("DATA") {
+                            in_data = true;
+                        } else if upper.starts_with("QUIT") {
+                            let _ = writer.write_all(b"221 bye\r\n");
+                            return;
+                        }
+                        if in_data { "354 end with ." } else { "250 localhost" }
+                    };
+                    if writer.write_all(format!("{}\r\n", reply).as_bytes()).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (port, rx)
+}
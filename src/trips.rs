@@ -0,0 +1,259 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Row};
+use serde::Serialize;
+use std::error::Error;
+
+use crate::history::{HistoryDb, Snapshot};
+
+// shorter movements are not reported as trip
+const MIN_TRIP_KM: f32 = 0.1;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS trips (
+    id INTEGER PRIMARY KEY,
+    vin TEXT NOT NULL,
+    start_time TEXT NOT NULL,
+    end_time TEXT NOT NULL,
+    start_latitude REAL,
+    start_longitude REAL,
+    end_latitude REAL,
+    end_longitude REAL,
+    start_mileage REAL NOT NULL,
+    end_mileage REAL NOT NULL,
+    start_soc INTEGER,
+    end_soc INTEGER,
+    energy_kwh REAL,
+    UNIQUE (vin, start_time)
+);
+";
+
+const COLUMNS: &str = "id, vin, start_time, end_time, start_latitude, start_longitude, end_latitude, end_longitude, \
+    start_mileage, end_mileage, start_soc, end_soc, energy_kwh";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Trip {
+    pub id: Option<i64>,
+    pub vin: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub start_position: Option<(f64, f64)>,
+    pub end_position: Option<(f64, f64)>,
+    pub start_mileage: f32,
+    pub end_mileage: f32,
+    pub start_soc: Option<u32>,
+    pub end_soc: Option<u32>,
+    pub energy_kwh: Option<f64>,
+}
+
+impl Trip {
+    fn between(start: &Snapshot, end: &Snapshot) -> Trip {
+        let energy_kwh = match (start.soc, end.soc, end.battery_capacity_kwh().or(start.battery_capacity_kwh())) {
+            (Some(a), Some(b), Some(capacity)) => Some((a as f64 - b as f64) / 100.0 * capacity),
+            _ => None,
+        };
+        Trip {
+            id: None,
+            vin: start.vin.to_owned(),
+            start_time: start.updated_at,
+            end_time: end.updated_at,
+            start_position: start.position(),
+            end_position: end.position(),
+            start_mileage: start.mileage,
+            end_mileage: end.mileage,
+            start_soc: start.soc,
+            end_soc: end.soc,
+            energy_kwh,
+        }
+    }
+
+    pub fn distance_km(&self) -> f32 {
+        self.end_mileage - self.start_mileage
+    }
+
+    pub fn duration(&self) -> chrono::Duration {
+        self.end_time - self.start_time
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Trip> {
+        let position = |lat: Option<f64>, lon: Option<f64>| lat.zip(lon);
+        Ok(Trip {
+            id: row.get(0)?,
+            vin: row.get(1)?,
+            start_time: row.get(2)?,
+            end_time: row.get(3)?,
+            start_position: position(row.get(4)?, row.get(5)?),
+            end_position: position(row.get(6)?, row.get(7)?),
+            start_mileage: row.get(8)?,
+            end_mileage: row.get(9)?,
+            start_soc: row.get(10)?,
+            end_soc: row.get(11)?,
+            energy_kwh: row.get(12)?,
+        })
+    }
+}
+
+/// Splits the snapshots of one vehicle, ordered by time, into trips.
+///
+/// A trip runs from the last parked snapshot before the vehicle was seen
+/// moving or with ignition on to the next parked snapshot. Mileage gained
+/// between two parked snapshots is a trip the polling did not catch.
+/// Trips still in progress at the end of the history are not returned.
+pub fn detect_trips(snapshots: &[Snapshot]) -> Vec<Trip> {
+    let mut trips = vec![];
+    let mut trip_start: Option<&Snapshot> = None;
+    let mut previous: Option<&Snapshot> = None;
+
+    for snapshot in snapshots {
+        let driving = snapshot.moving || snapshot.ignition_on();
+        match (trip_start, driving) {
+            (None, true) => trip_start = Some(previous.unwrap_or(snapshot)),
+            (Some(start), false) => {
+                trips.push(Trip::between(start, snapshot));
+                trip_start = None;
+            },
+            (None, false) => {
+                if let Some(previous) = previous {
+                    if snapshot.mileage - previous.mileage >= MIN_TRIP_KM {
+                        trips.push(Trip::between(previous, snapshot));
+                    }
+                }
+            },
+            (Some(_), true) => (),
+        }
+        previous = Some(snapshot);
+    }
+
+    trips.into_iter().filter(|t| t.distance_km() >= MIN_TRIP_KM).collect()
+}
+
+/// Stores the trips, already known trips are skipped. Returns the number of
+/// new trips.
+pub fn save_trips(db: &HistoryDb, trips: &[Trip]) -> Result<usize, Box<dyn Error>> {
+    let conn = db.connection();
+    conn.execute_batch(SCHEMA)?;
+    let mut inserted = 0;
+    for t in trips {
+        inserted += conn.execute(
+            "INSERT OR IGNORE INTO trips (vin, start_time, end_time, start_latitude, start_longitude, end_latitude, end_longitude, \
+                start_mileage, end_mileage, start_soc, end_soc, energy_kwh) \
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![t.vin, t.start_time, t.end_time, t.start_position.map(|p| p.0), t.start_position.map(|p| p.1),
+                t.end_position.map(|p| p.0), t.end_position.map(|p| p.1), t.start_mileage, t.end_mileage,
+                t.start_soc, t.end_soc, t.energy_kwh],
+        )?;
+    }
+    Ok(inserted)
+}
+
+/// Stored trips of the vehicle starting within `[from, to]`.
+pub fn load_trips(db: &HistoryDb, vin: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Trip>, Box<dyn Error>> {
+    let conn = db.connection();
+    conn.execute_batch(SCHEMA)?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM trips WHERE vin = ?1 AND start_time >= ?2 AND start_time <= ?3 ORDER BY start_time", COLUMNS))?;
+    let rows = stmt.query_map(params![vin, from, to], Trip::from_row)?;
+    Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+pub fn load_trip(db: &HistoryDb, id: i64) -> Result<Option<Trip>, Box<dyn Error>> {
+    let conn = db.connection();
+    conn.execute_batch(SCHEMA)?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM trips WHERE id = ?1", COLUMNS))?;
+    let mut rows = stmt.query_map(params![id], Trip::from_row)?;
+    Ok(rows.next().transpose()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{driving, snapshot, start};
+    use chrono::Duration;
+
+    #[test]
+    fn detects_trip_from_last_parked_to_next_parked_snapshot() {
+        let snapshots = vec![
+            snapshot(0, 100.0, 80),
+            driving(10, 105.0, 78),
+            driving(20, 120.0, 70),
+            snapshot(30, 125.0, 68),
+            snapshot(40, 125.0, 68),
+        ];
+        let trips = detect_trips(&snapshots);
+        assert_eq!(trips.len(), 1);
+        let trip = &trips[0];
+        assert_eq!(trip.start_time, start());
+        assert_eq!(trip.end_time, start() + Duration::minutes(30));
+        assert_eq!(trip.distance_km(), 25.0);
+        assert_eq!((trip.start_soc, trip.end_soc), (Some(80), Some(68)));
+        assert!((trip.energy_kwh.unwrap() - 0.12 * 46.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ignition_on_without_moving_starts_a_trip() {
+        let snapshots = vec![
+            snapshot(0, 100.0, 80),
+            Snapshot { ignition: "Start".to_owned(), ..snapshot(10, 100.0, 80) },
+            snapshot(20, 104.0, 78),
+        ];
+        let trips = detect_trips(&snapshots);
+        assert_eq!(trips.len(), 1);
+        assert_eq!(trips[0].distance_km(), 4.0);
+    }
+
+    #[test]
+    fn separates_consecutive_trips() {
+        let snapshots = vec![
+            snapshot(0, 100.0, 80),
+            driving(10, 110.0, 76),
+            snapshot(20, 115.0, 75),
+            driving(60, 120.0, 73),
+            snapshot(70, 130.0, 70),
+        ];
+        let trips = detect_trips(&snapshots);
+        assert_eq!(trips.len(), 2);
+        assert_eq!(trips[0].end_time, trips[1].start_time);
+        assert_eq!(trips[1].distance_km(), 15.0);
+    }
+
+    #[test]
+    fn mileage_gap_between_parked_snapshots_is_a_trip() {
+        let snapshots = vec![
+            snapshot(0, 100.0, 80),
+            snapshot(120, 140.0, 70),
+            // odometer rounding while parked
+            snapshot(180, 140.05, 70),
+        ];
+        let trips = detect_trips(&snapshots);
+        assert_eq!(trips.len(), 1);
+        assert_eq!(trips[0].start_time, start());
+        assert_eq!(trips[0].distance_km(), 40.0);
+    }
+
+    #[test]
+    fn trip_in_progress_is_not_returned() {
+        let snapshots = vec![snapshot(0, 100.0, 80), driving(10, 105.0, 78), driving(20, 120.0, 70)];
+        assert!(detect_trips(&snapshots).is_empty());
+    }
+
+    #[test]
+    fn movement_below_minimum_distance_is_ignored() {
+        let snapshots = vec![snapshot(0, 100.0, 80), driving(10, 100.0, 80), snapshot(20, 100.05, 80)];
+        assert!(detect_trips(&snapshots).is_empty());
+    }
+
+    #[test]
+    fn single_snapshot_has_no_trips() {
+        assert!(detect_trips(&[snapshot(0, 100.0, 80)]).is_empty());
+        assert!(detect_trips(&[driving(0, 100.0, 80)]).is_empty());
+        assert!(detect_trips(&[]).is_empty());
+    }
+
+    #[test]
+    fn history_starting_while_driving_begins_at_first_snapshot() {
+        let snapshots = vec![driving(0, 100.0, 80), snapshot(10, 108.0, 78)];
+        let trips = detect_trips(&snapshots);
+        assert_eq!(trips.len(), 1);
+        assert_eq!(trips[0].start_time, start());
+        assert_eq!(trips[0].distance_km(), 8.0);
+    }
+}
@@ -0,0 +1,278 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, error::Error, sync::mpsc::{self, Receiver, Sender}};
+
+use crate::charge_limit::ChargeLimitConfig;
+use crate::events::{self, VehicleEvent};
+use crate::geofence::{Geofence, GeofenceTracker};
+use crate::health::{HealthConfig, HealthMonitor};
+use crate::history::HistoryDb;
+use crate::psa::api::ApiClient;
+use crate::psa::model::{RemoteAction, VehicleStatus, VehiclesList, VehiclesListElement};
+
+// seconds until a vehicle is polled again after a remote action
+const COMMAND_FOLLOW_UP_POLL: i64 = 60;
+
+/// Poll intervals in seconds depending on the vehicle state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollSchedule {
+    pub parked: u64,
+    pub charging: u64,
+    pub moving: u64,
+}
+
+impl Default for PollSchedule {
+    fn default() -> Self {
+        PollSchedule {
+            parked: 1800,
+            charging: 300,
+            moving: 120,
+        }
+    }
+}
+
+impl PollSchedule {
+    pub fn interval(&self, status: &VehicleStatus) -> Duration {
+        let seconds = if status.kinetic.moving || status.ignition_on() {
+            self.moving
+        } else if status.is_charging() {
+            self.charging
+        } else {
+            self.parked
+        };
+        Duration::seconds(seconds as i64)
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct WatchConfig {
+    #[serde(default)]
+    pub schedule: PollSchedule,
+    // schedule overrides by VIN
+    #[serde(default)]
+    pub vehicles: BTreeMap<String, PollSchedule>,
+    // SoC levels in percent reported when crossed
+    #[serde(default)]
+    pub soc_thresholds: Vec<u32>,
+    #[serde(default)]
+    pub geofences: Vec<Geofence>,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub charge_limit: ChargeLimitConfig,
+}
+
+impl WatchConfig {
+    pub fn schedule_for(&self, vin: &str) -> &PollSchedule {
+        self.vehicles.get(vin).unwrap_or(&self.schedule)
+    }
+}
+
+/// Receives the snapshots and events of the watched vehicles.
+pub trait WatchHandler {
+    fn on_status(&mut self, _client: &mut ApiClient, _car: &VehiclesListElement, _status: &VehicleStatus) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, _client: &mut ApiClient, _car: &VehiclesListElement, _event: &VehicleEvent) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Time the handler needs a status of the vehicle earlier than the
+    /// schedule would poll it.
+    fn next_wakeup(&self, _car: &VehiclesListElement) -> Option<DateTime<Utc>> {
+        None
+    }
+}
+
+/// Prints every event to stdout.
+pub struct LogHandler;
+
+impl WatchHandler for LogHandler {
+    fn on_event(&mut self, _client: &mut ApiClient, _car: &VehiclesListElement, event: &VehicleEvent) -> Result<(), Box<dyn Error>> {
+        println!("{} {} {}", event.timestamp.to_rfc3339(), event.vin, event.kind);
+        Ok(())
+    }
+}
+
+/// Remote action requested from outside the poll loop, e.g. via MQTT.
+#[derive(Debug, Clone)]
+pub struct WatchCommand {
+    pub vin: String,
+    pub action: RemoteAction,
+}
+
+struct WatchedVehicle {
+    account: usize,
+    car: VehiclesListElement,
+    last: Option<VehicleStatus>,
+    next_poll: DateTime<Utc>,
+}
+
+pub struct Watcher<'a> {
+    config: WatchConfig,
+    clients: Vec<ApiClient<'a>>,
+    vehicles: Vec<WatchedVehicle>,
+    handlers: Vec<Box<dyn WatchHandler + 'a>>,
+    geofences: GeofenceTracker,
+    health: HealthMonitor,
+    command_tx: Sender<WatchCommand>,
+    command_rx: Receiver<WatchCommand>,
+}
+
+impl<'a> Watcher<'a> {
+    pub fn new(config: WatchConfig) -> Watcher<'a> {
+        let (command_tx, command_rx) = mpsc::channel();
+        Watcher {
+            geofences: GeofenceTracker::new(config.geofences.clone()),
+            health: HealthMonitor::new(config.health.clone()),
+            config,
+            clients: vec![],
+            vehicles: vec![],
+            handlers: vec![],
+            command_tx,
+            command_rx,
+        }
+    }
+
+    pub fn command_sender(&self) -> Sender<WatchCommand> {
+        self.command_tx.clone()
+    }
+
+    pub fn add_account(&mut self, client: ApiClient<'a>, cars: &VehiclesList) {
+        self.clients.push(client);
+        for car in &cars.vehicles {
+            self.vehicles.push(WatchedVehicle {
+                account: self.clients.len() - 1,
+                car: car.clone(),
+                last: None,
+                next_poll: Utc::now(),
+            });
+        }
+    }
+
+    /// Loads the recent history of the watched vehicles into the health
+    /// rules.
+    pub fn seed_health(&mut self, db: &HistoryDb) -> Result<(), Box<dyn Error>> {
+        for vehicle in &self.vehicles {
+            self.health.seed(&vehicle.car.vin, db)?;
+        }
+        Ok(())
+    }
+
+    pub fn add_handler(&mut self, handler: Box<dyn WatchHandler + 'a>) {
+        self.handlers.push(handler);
+    }
+
+    /// Polls all vehicles which are due and returns the time of the next poll.
+    pub fn poll_due(&mut self) -> DateTime<Utc> {
+        let Watcher { config, clients, vehicles, handlers, geofences, health, .. } = self;
+
+        for vehicle in vehicles.iter_mut().filter(|v| v.next_poll <= Utc::now()) {
+            let client = &mut clients[vehicle.account];
+            let schedule = config.schedule_for(&vehicle.car.vin);
+
+            let status = match client.connectedcar_get_vehicle_status(&vehicle.car.id) {
+                Ok(status) => status,
+                Err(e) => {
+                    eprintln!("{}: status request failed: {}", vehicle.car.vin, e);
+                    let interval = vehicle.last.as_ref().map_or(Duration::seconds(schedule.charging as i64), |s| schedule.interval(s));
+                    vehicle.next_poll = Utc::now() + interval;
+                    continue;
+                }
+            };
+
+            let mut events = match &vehicle.last {
+                Some(last) => events::diff_status(&vehicle.car.vin, last, &status, &config.soc_thresholds),
+                None => vec![],
+            };
+            events.extend(geofences.update(&vehicle.car.vin, &status));
+            events.extend(health.update(&vehicle.car.vin, &status));
+
+            for handler in handlers.iter_mut() {
+                if let Err(e) = handler.on_status(client, &vehicle.car, &status) {
+                    eprintln!("{}: handler failed: {}", vehicle.car.vin, e);
+                }
+                for event in &events {
+                    if let Err(e) = handler.on_event(client, &vehicle.car, event) {
+                        eprintln!("{}: handler failed: {}", vehicle.car.vin, e);
+                    }
+                }
+            }
+
+            let now = Utc::now();
+            vehicle.next_poll = handlers.iter()
+                .filter_map(|h| h.next_wakeup(&vehicle.car))
+                .filter(|t| *t > now)
+                .fold(now + schedule.interval(&status), |a, b| a.min(b));
+            vehicle.last = Some(status);
+        }
+
+        vehicles.iter().map(|v| v.next_poll).min().unwrap_or_else(|| Utc::now() + Duration::seconds(config.schedule.parked as i64))
+    }
+
+    /// Sends the remote action to the vehicle and schedules a poll to pick up
+    /// the result.
+    pub fn execute(&mut self, command: &WatchCommand) -> Result<(), Box<dyn Error>> {
+        let vehicle = match self.vehicles.iter_mut().find(|v| v.car.vin.eq(&command.vin)) {
+            Some(vehicle) => vehicle,
+            None => return Err(format!("unknown vehicle {}", command.vin).into()),
+        };
+        self.clients[vehicle.account].connectedcar_remote_action(&vehicle.car.id, command.action)?;
+        vehicle.next_poll = vehicle.next_poll.min(Utc::now() + Duration::seconds(COMMAND_FOLLOW_UP_POLL));
+        Ok(())
+    }
+
+    /// Polls forever, `after_poll` is called after each round, e.g. to persist
+    /// refreshed tokens. Commands are executed while waiting for the next poll.
+    pub fn run<F>(&mut self, mut after_poll: F) -> Result<(), Box<dyn Error>> where F: FnMut() -> Result<(), Box<dyn Error>> {
+        loop {
+            let next_poll = self.poll_due();
+            after_poll()?;
+            let wait = (next_poll - Utc::now()).to_std().unwrap_or_default();
+            // the watcher keeps a sender itself, so this only ends by timeout
+            if let Ok(command) = self.command_rx.recv_timeout(wait) {
+                match self.execute(&command) {
+                    Ok(()) => println!("{} {} sent", command.vin, command.action.name()),
+                    Err(e) => eprintln!("{}: {} failed: {}", command.vin, command.action.name(), e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{start, status};
+
+    #[test]
+    fn polls_by_vehicle_state() {
+        let schedule = PollSchedule::default();
+        let mut charging = status(start());
+        charging.energy[0].extension.as_mut().unwrap().electric.charging.status = "InProgress".to_owned();
+        let mut driving = status(start());
+        driving.kinetic.moving = true;
+        driving.ignition._type = "Drive".to_owned();
+        let mut ignition_on = charging.clone();
+        ignition_on.ignition._type = "StartUp".to_owned();
+
+        let cases = [
+            ("parked", status(start()), 1800),
+            ("charging", charging, 300),
+            ("driving", driving, 120),
+            ("ignition on while charging", ignition_on, 120),
+        ];
+        for (name, status, seconds) in cases {
+            assert_eq!(schedule.interval(&status), Duration::seconds(seconds), "{}", name);
+        }
+    }
+
+    #[test]
+    fn uses_schedule_of_the_vehicle() {
+        let fast = PollSchedule { parked: 60, charging: 30, moving: 10 };
+        let config = WatchConfig { vehicles: BTreeMap::from([("VIN1".to_owned(), fast)]), ..Default::default() };
+        assert_eq!(config.schedule_for("VIN1").parked, 60);
+        assert_eq!(config.schedule_for("VIN2").parked, 1800);
+    }
+}